@@ -1,94 +1,437 @@
-use std::collections::{HashMap, HashSet};
+use std::{cmp::Ordering, collections::HashMap, hash::Hash};
 
+/// Builds a [`Graph`] by interning the nodes to dense integer ids.
 #[derive(Debug)]
-struct PageRank {
-    graph: HashMap<String, HashSet<String>>,
-    ranks: HashMap<String, f32>,
-    damping_factor: f32,
-    epsilon: f32,
+pub struct GraphBuilder<N> {
+    nodes: Vec<N>,
+    index: HashMap<N, u32>,
+    edges: Vec<(u32, u32)>,
+}
+
+impl<N: Clone + Eq + Hash> GraphBuilder<N> {
+    pub fn new() -> Self {
+        GraphBuilder {
+            nodes: Vec::new(),
+            index: HashMap::new(),
+            edges: Vec::new(),
+        }
+    }
+
+    /// Returns the dense id of the node, adding it if it is not known yet.
+    pub fn add_node(&mut self, node: N) -> u32 {
+        if let Some(&id) = self.index.get(&node) {
+            return id;
+        }
+        let id = u32::try_from(self.nodes.len()).expect("Graph has more than u32::MAX nodes");
+        self.nodes.push(node.clone());
+        self.index.insert(node, id);
+        id
+    }
+
+    /// Adds a link pointing from `from` to `to`.
+    pub fn add_edge(&mut self, from: N, to: N) {
+        let from = self.add_node(from);
+        let to = self.add_node(to);
+        self.edges.push((from, to));
+    }
+
+    /// Turns the collected edges into compressed sparse rows of in-links.
+    ///
+    /// Duplicate edges are only counted once.
+    pub fn build(mut self) -> Graph<N> {
+        // Sorting by target groups the in-links of each node into one row
+        self.edges
+            .sort_unstable_by_key(|&(source, target)| (target, source));
+        self.edges.dedup();
+
+        let node_count = self.nodes.len();
+        let mut in_offsets = vec![0; node_count + 1];
+        let mut out_degree = vec![0; node_count];
+        let mut in_sources = Vec::with_capacity(self.edges.len());
+        for &(source, target) in &self.edges {
+            in_offsets[target as usize + 1] += 1;
+            out_degree[source as usize] += 1;
+            in_sources.push(source);
+        }
+        for i in 0..node_count {
+            in_offsets[i + 1] += in_offsets[i];
+        }
+
+        Graph {
+            nodes: self.nodes,
+            index: self.index,
+            in_offsets,
+            in_sources,
+            out_degree,
+        }
+    }
+}
+
+impl<N: Clone + Eq + Hash> Default for GraphBuilder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A directed graph stored as compressed sparse rows of in-links.
+#[derive(Debug)]
+pub struct Graph<N> {
+    nodes: Vec<N>,
+    index: HashMap<N, u32>,
+    /// The in-links of node `i` are `in_sources[in_offsets[i]..in_offsets[i + 1]]`
+    in_offsets: Vec<usize>,
+    in_sources: Vec<u32>,
+    out_degree: Vec<u32>,
 }
 
-impl PageRank {
-    fn new(graph: HashMap<String, HashSet<String>>) -> Self {
-        let node_count = graph.len() as f32;
-        let damping_factor = 0.85;
-        let epsilon = 0.0001;
+impl<N> Graph<N> {
+    pub const fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn in_links(&self, node: usize) -> &[u32] {
+        &self.in_sources[self.in_offsets[node]..self.in_offsets[node + 1]]
+    }
+}
+
+/// Where a random jump may land.
+enum Teleport {
+    /// Every node is equally likely.
+    Uniform(f32),
+    /// The probability of each node.
+    Personalized(Vec<f32>),
+}
+
+impl Teleport {
+    fn get(&self, node: usize) -> f32 {
+        match self {
+            Teleport::Uniform(probability) => *probability,
+            Teleport::Personalized(probabilities) => probabilities[node],
+        }
+    }
+}
 
-        let rank = 1.0 / node_count;
+#[derive(Debug)]
+pub struct PageRank<N> {
+    graph: Graph<N>,
+    ranks: Vec<f32>,
+    damping_factor: f32,
+    epsilon: f32,
+    max_iterations: usize,
+    threads: usize,
+}
 
-        let ranks = graph
-            .keys()
-            .map(|k| (k.clone(), rank))
-            .collect::<HashMap<_, _>>();
+#[allow(clippy::cast_precision_loss)]
+impl<N: Clone + Eq + Hash + Sync> PageRank<N> {
+    pub fn new(graph: Graph<N>) -> Self {
+        let rank = 1.0 / graph.len() as f32;
 
         PageRank {
+            ranks: vec![rank; graph.len()],
             graph,
-            ranks,
-            damping_factor,
-            epsilon,
+            damping_factor: 0.85,
+            epsilon: 0.0001,
+            max_iterations: 100,
+            threads: 1,
         }
     }
 
-    fn compute(&mut self) {
-        let mut error = f32::INFINITY;
-        let mut new_ranks = HashMap::new();
-
-        while error > self.epsilon {
-            new_ranks.clear();
+    /// Splits each step of the power iteration across the given amount of threads.
+    #[must_use]
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
 
-            let dangling_nodes_sum = self.compute_dangling_nodes_sum();
+    pub const fn len(&self) -> usize {
+        self.graph.len()
+    }
 
-            for (node_id, neighbors) in &self.graph {
-                let mut rank = (1.0 - self.damping_factor) / self.graph.len() as f32;
+    pub const fn is_empty(&self) -> bool {
+        self.graph.is_empty()
+    }
 
-                for neighbor_id in neighbors.iter() {
-                    let neighbor_rank = self.ranks.get(neighbor_id).unwrap_or(&0.0);
-                    let neighbor_outdegree =
-                        self.graph.get(neighbor_id).map_or(0.0, |n| n.len() as f32);
-                    rank += self.damping_factor * neighbor_rank / neighbor_outdegree;
-                }
+    pub fn compute(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        let teleport = Teleport::Uniform(1.0 / self.graph.len() as f32);
+        let mut ranks = std::mem::take(&mut self.ranks);
+        self.iterate(&mut ranks, &teleport);
+        self.ranks = ranks;
+    }
 
-                rank += self.damping_factor * dangling_nodes_sum / self.graph.len() as f32;
-                new_ranks.insert(node_id.clone(), rank);
+    /// Computes a personalized `PageRank` where random jumps only land on the given nodes.
+    ///
+    /// The personalization weights don't need to be normalized. The global ranks are used as
+    /// the starting point and stay untouched. Returns the `n` highest ranked nodes.
+    pub fn compute_personalized(
+        &self,
+        personalization: &HashMap<N, f32>,
+        n: usize,
+    ) -> Vec<(N, f32)> {
+        let mut teleport = vec![0.0; self.graph.len()];
+        let mut total = 0.0;
+        for (node, weight) in personalization {
+            if let Some(&id) = self.graph.index.get(node) {
+                teleport[id as usize] += weight;
+                total += weight;
             }
+        }
+        if total <= 0.0 {
+            return Vec::new();
+        }
+        for probability in &mut teleport {
+            *probability /= total;
+        }
+
+        let mut ranks = self.ranks.clone();
+        self.iterate(&mut ranks, &Teleport::Personalized(teleport));
+        self.top_n_of(&ranks, n)
+    }
 
-            let mut max_error = 0.0;
-            for (node_id, rank) in &new_ranks {
-                let old_rank = self.ranks.get(node_id).unwrap_or(&0.0);
-                let error = (rank - old_rank).abs();
+    /// Runs the power iteration until it converges.
+    fn iterate(&self, ranks: &mut Vec<f32>, teleport: &Teleport) {
+        let node_count = self.graph.len();
+        let chunk_size = node_count.div_ceil(self.threads).max(1);
+        let mut contributions = vec![0.0; node_count];
+        let mut new_ranks = vec![0.0; node_count];
 
-                if error > max_error {
-                    max_error = error;
+        for _ in 0..self.max_iterations {
+            let mut dangling_nodes_sum = 0.0;
+            for (node, contribution) in contributions.iter_mut().enumerate() {
+                let out_degree = self.graph.out_degree[node];
+                if out_degree == 0 {
+                    dangling_nodes_sum += ranks[node];
+                    *contribution = 0.0;
+                } else {
+                    *contribution = ranks[node] / out_degree as f32;
                 }
             }
 
-            error = max_error;
-            self.ranks = new_ranks.clone();
+            let old_ranks = &*ranks;
+            let contributions = &contributions;
+            let error = if self.threads == 1 {
+                self.step(
+                    0,
+                    &mut new_ranks,
+                    old_ranks,
+                    contributions,
+                    dangling_nodes_sum,
+                    teleport,
+                )
+            } else {
+                std::thread::scope(|scope| {
+                    let workers: Vec<_> = new_ranks
+                        .chunks_mut(chunk_size)
+                        .enumerate()
+                        .map(|(chunk, new_ranks)| {
+                            scope.spawn(move || {
+                                self.step(
+                                    chunk * chunk_size,
+                                    new_ranks,
+                                    old_ranks,
+                                    contributions,
+                                    dangling_nodes_sum,
+                                    teleport,
+                                )
+                            })
+                        })
+                        .collect();
+                    workers
+                        .into_iter()
+                        .map(|worker| worker.join().expect("PageRank worker panicked"))
+                        .fold(0.0, f32::max)
+                })
+            };
+
+            std::mem::swap(ranks, &mut new_ranks);
+            if error <= self.epsilon {
+                break;
+            }
         }
     }
 
-    fn compute_dangling_nodes_sum(&self) -> f32 {
-        let mut sum = 0.0;
-
-        for (node_id, neighbors) in &self.graph {
-            if neighbors.is_empty() {
-                sum += self.ranks.get(node_id).unwrap_or(&0.0);
-            }
+    /// Computes the new ranks of the nodes starting at `offset` and returns the largest change.
+    fn step(
+        &self,
+        offset: usize,
+        new_ranks: &mut [f32],
+        ranks: &[f32],
+        contributions: &[f32],
+        dangling_nodes_sum: f32,
+        teleport: &Teleport,
+    ) -> f32 {
+        let mut max_error: f32 = 0.0;
+        for (i, new_rank) in new_ranks.iter_mut().enumerate() {
+            let node = offset + i;
+            let inbound: f32 = self
+                .graph
+                .in_links(node)
+                .iter()
+                .map(|&source| contributions[source as usize])
+                .sum();
+            let teleport = teleport.get(node);
+            let random_jump = (1.0 - self.damping_factor) * teleport;
+            *new_rank = self
+                .damping_factor
+                .mul_add(dangling_nodes_sum.mul_add(teleport, inbound), random_jump);
+            max_error = max_error.max((*new_rank - ranks[node]).abs());
         }
+        max_error
+    }
 
-        sum
+    /// Returns the rank of the node if it is part of the graph.
+    pub fn rank(&self, node: &N) -> Option<f32> {
+        self.graph
+            .index
+            .get(node)
+            .map(|&id| self.ranks[id as usize])
+    }
+
+    pub fn top_n(&self, n: usize) -> Vec<N> {
+        self.top_n_of(&self.ranks, n)
+            .into_iter()
+            .map(|(node, _)| node)
+            .collect()
     }
 
-    fn top_n(&self, n: usize) -> Vec<String> {
-        let mut rank_vec: Vec<(String, f32)> =
-            self.ranks.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    fn top_n_of(&self, ranks: &[f32], n: usize) -> Vec<(N, f32)> {
+        let by_rank = |a: &(usize, f32), b: &(usize, f32)| -> Ordering { b.1.total_cmp(&a.1) };
+        let mut rank_vec: Vec<(usize, f32)> = ranks.iter().copied().enumerate().collect();
 
-        rank_vec.sort_by(|a, b| b.1.partial_cmp(&a.1).expect("Invalid comparison"));
+        // Only fully sort the part we return since the graph might be huge
+        if n < rank_vec.len() {
+            rank_vec.select_nth_unstable_by(n, by_rank);
+            rank_vec.truncate(n);
+        }
+        rank_vec.sort_by(by_rank);
 
         rank_vec
-            .iter()
-            .take(n)
-            .map(|(node_id, _)| node_id.clone())
+            .into_iter()
+            .map(|(node, rank)| (self.graph.nodes[node].clone(), rank))
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f32 = 1e-3;
+
+    fn graph(edges: &[(&'static str, &'static str)]) -> Graph<&'static str> {
+        let mut builder = GraphBuilder::new();
+        for &(from, to) in edges {
+            builder.add_edge(from, to);
+        }
+        builder.build()
+    }
+
+    fn assert_rank(page_rank: &PageRank<&'static str>, node: &'static str, expected: f32) {
+        let rank = page_rank.rank(&node).expect("Node is part of the graph");
+        assert!(
+            (rank - expected).abs() < TOLERANCE,
+            "rank of {node} is {rank}, expected {expected}"
+        );
+    }
+
+    #[test]
+    fn cycle_ranks_are_uniform() {
+        let mut page_rank = PageRank::new(graph(&[("a", "b"), ("b", "c"), ("c", "a")]));
+        page_rank.compute();
+
+        for node in ["a", "b", "c"] {
+            assert_rank(&page_rank, node, 1.0 / 3.0);
+        }
+    }
+
+    #[test]
+    fn dangling_node_rank_is_redistributed() {
+        // `c` has no out-links, so its rank is spread over all nodes. Solving the equations for
+        // a damping factor of 0.85 gives 10/47 for `a` and `b` and 27/47 for `c`.
+        let mut page_rank = PageRank::new(graph(&[("a", "c"), ("b", "c")]));
+        page_rank.compute();
+
+        assert_rank(&page_rank, "a", 10.0 / 47.0);
+        assert_rank(&page_rank, "b", 10.0 / 47.0);
+        assert_rank(&page_rank, "c", 27.0 / 47.0);
+        assert_eq!(page_rank.top_n(1), vec!["c"]);
+    }
+
+    #[test]
+    fn duplicate_edges_count_once() {
+        let mut page_rank = PageRank::new(graph(&[("a", "c"), ("a", "c"), ("b", "c")]));
+        page_rank.compute();
+
+        assert_rank(&page_rank, "a", 10.0 / 47.0);
+        assert_rank(&page_rank, "c", 27.0 / 47.0);
+    }
+
+    /// A graph with enough nodes to be split across threads where some links lead nowhere.
+    fn larger_graph() -> Graph<u32> {
+        let mut builder = GraphBuilder::new();
+        for i in 0..100 {
+            builder.add_edge(i, (i * 7 + 3) % 100);
+            builder.add_edge(i, (i * 13 + 1) % 100);
+            if i % 10 == 0 {
+                builder.add_edge(i, 100 + i);
+            }
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn threads_give_the_same_ranks() {
+        let mut single = PageRank::new(larger_graph());
+        single.compute();
+        let total: f32 = (0..200).filter_map(|node| single.rank(&node)).sum();
+        assert!((total - 1.0).abs() < TOLERANCE);
+
+        for threads in [2, 3, 8] {
+            let mut threaded = PageRank::new(larger_graph()).with_threads(threads);
+            threaded.compute();
+
+            for node in 0..200 {
+                assert_eq!(
+                    threaded.rank(&node),
+                    single.rank(&node),
+                    "{threads} threads"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn personalized_ranks_stay_near_the_seeds() {
+        let mut page_rank = PageRank::new(graph(&[("a", "b"), ("b", "a"), ("c", "d"), ("d", "c")]));
+        page_rank.compute();
+
+        let top = page_rank.compute_personalized(&HashMap::from([("a", 1.0)]), 2);
+        let nodes: Vec<_> = top.iter().map(|&(node, _)| node).collect();
+        assert_eq!(nodes, vec!["a", "b"]);
+        // `a` keeps the random jumps, `b` only gets what `a` links to it
+        assert!((top[0].1 - 1.0 / 1.85).abs() < TOLERANCE);
+        assert!((top[1].1 - 0.85 / 1.85).abs() < TOLERANCE);
+
+        // The global ranks are not touched
+        assert_rank(&page_rank, "c", 0.25);
+    }
+
+    #[test]
+    fn personalized_without_known_seeds_is_empty() {
+        let mut page_rank = PageRank::new(graph(&[("a", "b")]));
+        page_rank.compute();
+
+        assert!(page_rank
+            .compute_personalized(&HashMap::from([("unknown", 1.0)]), 10)
+            .is_empty());
+        assert!(page_rank
+            .compute_personalized(&HashMap::from([("a", 0.0)]), 10)
+            .is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::error;
+
+use crate::{
+    query::{self, QueryError},
+    search::{self, Hit},
+    AppState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    query: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResponse {
+    query: String,
+    count: usize,
+    hits: Vec<Hit>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimilarImagesParams {
+    event_id: Option<String>,
+    limit: Option<usize>,
+    /// How many of the 64 bits of the image hashes may differ.
+    max_distance: Option<u8>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SimilarImagesResponse {
+    event_id: String,
+    count: usize,
+    hits: Vec<Hit>,
+}
+
+/// The JSON body returned for every failed API request.
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    errcode: &'static str,
+    error: String,
+}
+
+/// Errors of the JSON API. These are serialized as [`ErrorBody`] instead of plain text.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("Missing query parameter")]
+    MissingQuery,
+    #[error("Missing event_id parameter")]
+    MissingEventId,
+    #[error("No hashed image with the event id {0} is indexed")]
+    UnknownImage(String),
+    #[error("The limit has to be between 1 and {}", search::MAX_LIMIT)]
+    InvalidLimit,
+    #[error("Invalid query parameters: {0}")]
+    InvalidParameters(#[from] QueryRejection),
+    #[error("Invalid query: {0}")]
+    InvalidQuery(#[from] QueryError),
+    /// The details are only logged since they may reveal internals like database addresses.
+    #[error("Internal server error")]
+    Internal(color_eyre::Report),
+}
+
+impl ApiError {
+    const fn errcode(&self) -> &'static str {
+        match self {
+            ApiError::MissingQuery | ApiError::MissingEventId => "KS_MISSING_PARAM",
+            ApiError::UnknownImage(_) => "KS_NOT_FOUND",
+            ApiError::InvalidLimit | ApiError::InvalidParameters(_) => "KS_INVALID_PARAM",
+            ApiError::InvalidQuery(_) => "KS_INVALID_QUERY",
+            ApiError::Internal(_) => "KS_UNKNOWN",
+        }
+    }
+
+    const fn status(&self) -> StatusCode {
+        match self {
+            ApiError::MissingQuery
+            | ApiError::MissingEventId
+            | ApiError::InvalidLimit
+            | ApiError::InvalidParameters(_)
+            | ApiError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+            ApiError::UnknownImage(_) => StatusCode::NOT_FOUND,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<color_eyre::Report> for ApiError {
+    fn from(err: color_eyre::Report) -> Self {
+        Self::Internal(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let ApiError::Internal(err) = &self {
+            error!("Search API request failed: {:?}", err);
+        }
+        let body = ErrorBody {
+            errcode: self.errcode(),
+            error: self.to_string(),
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+/// The requested number of hits. It also bounds how much work a single request can cause.
+fn limit(limit: Option<usize>) -> Result<usize, ApiError> {
+    let limit = limit.unwrap_or(search::DEFAULT_LIMIT);
+    if (1..=search::MAX_LIMIT).contains(&limit) {
+        Ok(limit)
+    } else {
+        Err(ApiError::InvalidLimit)
+    }
+}
+
+pub async fn search(
+    State(state): State<Arc<AppState>>,
+    params: Result<Query<SearchParams>, QueryRejection>,
+) -> Result<Json<SearchResponse>, ApiError> {
+    let Query(params) = params?;
+    let Some(query) = params.query else {
+        return Err(ApiError::MissingQuery);
+    };
+
+    let limit = limit(params.limit)?;
+    let parsed = query::parse(&query)?;
+    let hits = search::search(&state, &parsed, limit).await?;
+
+    Ok(Json(SearchResponse {
+        query,
+        count: hits.len(),
+        hits,
+    }))
+}
+
+pub async fn similar_images(
+    State(state): State<Arc<AppState>>,
+    params: Result<Query<SimilarImagesParams>, QueryRejection>,
+) -> Result<Json<SimilarImagesResponse>, ApiError> {
+    let Query(params) = params?;
+    let Some(event_id) = params.event_id else {
+        return Err(ApiError::MissingEventId);
+    };
+
+    let limit = limit(params.limit)?;
+    let max_distance = params
+        .max_distance
+        .unwrap_or(search::DEFAULT_MAX_IMAGE_DISTANCE);
+    let Some(hits) = search::similar_images(&state, &event_id, max_distance, limit).await? else {
+        return Err(ApiError::UnknownImage(event_id));
+    };
+
+    Ok(Json(SimilarImagesResponse {
+        event_id,
+        count: hits.len(),
+        hits,
+    }))
+}
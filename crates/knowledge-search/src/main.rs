@@ -15,7 +15,6 @@
     clippy::suboptimal_flops,
     clippy::fn_to_numeric_cast_any,
     clippy::if_then_some_else_none,
-    clippy::imprecise_flops,
     clippy::lossy_float_literal,
     clippy::panic_in_result_fn,
     clippy::clone_on_ref_ptr
@@ -34,12 +33,18 @@ use axum::{
     Router,
 };
 use color_eyre::Result;
+use ranking::Ranks;
 use tera::Tera;
 use thiserror::Error;
-use tracing::info;
-use utils::indradb_proto::Client;
+use tokio::sync::RwLock;
+use tracing::{error, info};
+use utils::{indradb_proto::Client, text_index::TextIndex};
 
 mod algos;
+mod api;
+mod query;
+mod ranking;
+mod search;
 
 const INDEX_TEMPLATE: &str = r#"
 <form method="get" action="/results">
@@ -48,39 +53,90 @@ const INDEX_TEMPLATE: &str = r#"
 </form>
 "#;
 
-const RESULTS_TEMPLATE: &str = r#"
-<h1>Event: {{ event_id }}</h1>
-<p>{{ text_message_body }}</p>
-<h3>Properties</h3>
-<table>
-    <tr>
-        <th>name</th>
-        <th>value</th>
-    </tr>
-    {% for prop in properties %}
-        <tr>
-            <td>{{ prop.0 }}</td>
-            <td>{{ prop.1 }}</td>
-        </tr>
-    {% endfor %}
-</table>
-{% if room_id %}
-    <h3>Linked room</h3>
-    {% if room_name%}
-    <h4>{{ room_name }}</h4>
+const RESULTS_TEMPLATE: &str = r"
+<h1>Results for: {{ query }}</h1>
+{% if hits | length == 0 %}
+    <p>No results found.</p>
+{% endif %}
+{% for hit in hits %}
+    {% if hit.event_id %}
+    <h2>Event: {{ hit.event_id }}</h2>
     {% else %}
-    <h4>{{ room_id }}</h4>
+    <h2>{{ hit.vertex_type }}: {{ hit.uuid }}</h2>
     {% endif %}
-
-    {% if room_topic%}
-    <p>{{ room_topic }}</p>
+    {% if hit.thread_root %}
+        {% if hit.thread_root.text_message_body %}
+        <p><i>In thread: {{ hit.thread_root.text_message_body }}</i></p>
+        {% else %}
+        <p><i>In thread: {{ hit.thread_root.event_id }}</i></p>
+        {% endif %}
     {% endif %}
-{% endif %}
-"#;
+    {% if hit.in_reply_to %}
+        {% if hit.in_reply_to.text_message_body %}
+        <blockquote>{{ hit.in_reply_to.text_message_body }}</blockquote>
+        {% else %}
+        <blockquote>In reply to {{ hit.in_reply_to.event_id }}</blockquote>
+        {% endif %}
+    {% endif %}
+    {% if hit.text_message_body %}
+    <p>{{ hit.text_message_body }}</p>
+    {% elif hit.properties.redacted %}
+    <p><i>This message was redacted.</i></p>
+    {% endif %}
+    {% if hit.properties.media_mimetype %}
+    <p><small>
+        {{ hit.properties.media_mimetype }}
+        {% if hit.properties.media_size %}, {{ hit.properties.media_size | filesizeformat }}{% endif %}
+    </small></p>
+    {% endif %}
+    {% if hit.revisions | length > 0 %}
+        <details>
+            <summary>Edit history</summary>
+            <ol>
+            {% for revision in hit.revisions %}
+                <li>{{ revision.text_message_body }} <small>{{ revision.event_id }}</small></li>
+            {% endfor %}
+            </ol>
+        </details>
+    {% endif %}
+    <h3>Properties</h3>
+    <table>
+        <tr>
+            <th>name</th>
+            <th>value</th>
+        </tr>
+        {% for name, value in hit.properties %}
+            <tr>
+                <td>{{ name }}</td>
+                <td>{{ value }}</td>
+            </tr>
+        {% endfor %}
+    </table>
+    {% if hit.room %}
+        <h3>Linked room</h3>
+        {% if hit.room.name %}
+        <h4>{{ hit.room.name }}</h4>
+        {% elif hit.room.room_id %}
+        <h4>{{ hit.room.room_id }}</h4>
+        {% else %}
+        <h4>{{ hit.room.uuid }}</h4>
+        {% endif %}
+
+        {% if hit.room.canonical_alias %}
+        <p>{{ hit.room.canonical_alias }}</p>
+        {% endif %}
+        {% if hit.room.topic %}
+        <p>{{ hit.room.topic }}</p>
+        {% endif %}
+    {% endif %}
+{% endfor %}
+";
 
 pub struct AppState {
     tera: Tera,
     indradb: Client,
+    text_index: RwLock<TextIndex>,
+    ranks: RwLock<Ranks>,
 }
 
 #[tokio::main]
@@ -93,11 +149,23 @@ async fn main() -> Result<()> {
 
     let indradb = utils::get_client_retrying("grpc://127.0.0.1:27615".to_string()).await?;
 
-    let shared_state = Arc::new(AppState { tera, indradb });
+    let text_index = TextIndex::load(utils::get_text_index_path())?;
+    info!("Loaded text index with {} documents", text_index.len());
+
+    let shared_state = Arc::new(AppState {
+        tera,
+        indradb,
+        text_index: RwLock::new(text_index),
+        ranks: RwLock::default(),
+    });
+    ranking::spawn_rank_job(Arc::clone(&shared_state));
+    search::spawn_text_index_job(Arc::clone(&shared_state));
 
     let app = Router::new()
         .route("/", get(index))
         .route("/results", get(results))
+        .route("/api/v1/search", get(api::search))
+        .route("/api/v1/similar_images", get(api::similar_images))
         .with_state(shared_state);
 
     // run it with hyper on localhost:3000
@@ -108,20 +176,21 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-#[allow(clippy::unused_async)]
 async fn results(
     State(state): State<Arc<AppState>>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<Html<String>, AppError> {
-    let query = params.get("query");
-    if query.is_none() {
-        return Err(AppError(color_eyre::eyre::eyre!("Missing query parameter")));
-    }
+    let Some(query) = params.get("query") else {
+        return Err(AppError::bad_request(color_eyre::eyre::eyre!(
+            "Missing query parameter"
+        )));
+    };
+    let parsed = query::parse(query).map_err(AppError::bad_request)?;
+    let hits = search::search(&state, &parsed, search::DEFAULT_LIMIT).await?;
+
     let mut context = tera::Context::new();
-    context.insert("text_message_body", "Blub");
-    context.insert("event_id", "$abc");
-    context.insert("properties", &[("a", "b")]);
-    context.insert("room_id", "!abc");
+    context.insert("query", query);
+    context.insert("hits", &hits);
     let rendered = state.tera.render("results.html", &context)?;
 
     Ok(Html(rendered))
@@ -150,16 +219,24 @@ impl IntoResponse for TeraError {
 }
 
 // Make our own error that wraps `color_eyre::Error`.
-struct AppError(color_eyre::Report);
+struct AppError(StatusCode, color_eyre::Report);
+
+impl AppError {
+    /// An error caused by the request itself, like an invalid query.
+    fn bad_request(err: impl Into<color_eyre::Report>) -> Self {
+        Self(StatusCode::BAD_REQUEST, err.into())
+    }
+}
 
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        if self.0 == StatusCode::BAD_REQUEST {
+            return (self.0, self.1.to_string()).into_response();
+        }
+        // The report may reveal internals like database addresses, so it only goes to the log
+        error!("Request failed: {:?}", self.1);
+        (self.0, "Something went wrong").into_response()
     }
 }
 
@@ -170,6 +247,6 @@ where
     E: Into<color_eyre::Report>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        Self(StatusCode::INTERNAL_SERVER_ERROR, err.into())
     }
 }
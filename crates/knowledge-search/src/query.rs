@@ -0,0 +1,360 @@
+use thiserror::Error;
+
+/// A piece of free text the hits have to contain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Term {
+    Word(String),
+    /// A quoted phrase which has to appear as is.
+    Phrase(String),
+}
+
+impl Term {
+    pub fn text(&self) -> &str {
+        match self {
+            Term::Word(text) | Term::Phrase(text) => text,
+        }
+    }
+}
+
+/// A parsed search query.
+///
+/// Free text terms are grouped into clauses separated by `OR`. A hit has to contain all terms of
+/// at least one clause and has to pass all filters.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SearchQuery {
+    pub clauses: Vec<Vec<Term>>,
+    /// Terms prefixed with `-` which a hit must not contain.
+    pub excluded: Vec<Term>,
+    /// `room:` filters. A hit has to be in any of these rooms.
+    pub rooms: Vec<String>,
+    /// `space:` filters. A hit has to be in any room of these spaces, including nested spaces.
+    pub spaces: Vec<String>,
+    /// `member:` filters. A hit has to be in a room all of these users joined.
+    pub members: Vec<String>,
+    /// `from:` filters. A hit has to be sent by any of these users.
+    pub senders: Vec<String>,
+    /// `type:` filters as vertex types. A hit has to be of any of these types.
+    pub types: Vec<&'static str>,
+    /// `before:` filter in milliseconds since the unix epoch.
+    pub before: Option<u64>,
+    /// `after:` filter in milliseconds since the unix epoch.
+    pub after: Option<u64>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum QueryError {
+    #[error(
+        "The query is empty. Provide search terms or a room:, space:, member: or from: filter"
+    )]
+    Empty,
+    #[error("The filter \"{0}:\" is missing a value")]
+    MissingValue(String),
+    #[error("Invalid date \"{0}\". Dates have to be in the format YYYY-MM-DD")]
+    InvalidDate(String),
+    #[error(
+        "Unknown type \"{0}\". Supported are text, notice, emote, image, file, video, audio, \
+         location, room and user"
+    )]
+    UnknownType(String),
+    #[error("Filters can not be excluded: \"-{0}:\"")]
+    ExcludedFilter(String),
+    #[error("OR has to be placed between two search terms")]
+    DanglingOr,
+    #[error("Unterminated quote")]
+    UnterminatedQuote,
+}
+
+const FILTERS: [&str; 7] = ["room", "space", "member", "from", "before", "after", "type"];
+
+/// A single whitespace separated token of the raw query.
+struct Token {
+    negated: bool,
+    field: Option<String>,
+    value: String,
+    quoted: bool,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            break;
+        };
+
+        let negated = first == '-';
+        if negated {
+            chars.next();
+        }
+
+        let mut field = None;
+        let mut value = String::new();
+        let mut quoted = false;
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            match c {
+                '"' if value.is_empty() => {
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some(c) => value.push(c),
+                            None => return Err(QueryError::UnterminatedQuote),
+                        }
+                    }
+                }
+                // Anything else containing a colon, like urls, is a plain word
+                ':' if field.is_none() && !quoted && FILTERS.contains(&value.as_str()) => {
+                    field = Some(std::mem::take(&mut value));
+                }
+                c => value.push(c),
+            }
+        }
+
+        tokens.push(Token {
+            negated,
+            field,
+            value,
+            quoted,
+        });
+    }
+
+    Ok(tokens)
+}
+
+/// Parses a `YYYY-MM-DD` date into milliseconds since the unix epoch at midnight UTC.
+fn parse_date(value: &str) -> Result<u64, QueryError> {
+    let invalid = || QueryError::InvalidDate(value.to_string());
+    let mut parts = value.splitn(3, '-');
+    let mut next = || -> Result<i64, QueryError> {
+        parts
+            .next()
+            .and_then(|part| part.parse().ok())
+            .ok_or_else(invalid)
+    };
+    let (year, month, day) = (next()?, next()?, next()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+
+    // Days from civil as described at http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    u64::try_from(days * 86_400_000).map_err(|_| invalid())
+}
+
+fn parse_type(value: &str) -> Result<&'static str, QueryError> {
+    match value {
+        "text" => Ok("text_message_event"),
+        "notice" => Ok("notice_message_event"),
+        "emote" => Ok("emote_message_event"),
+        "image" => Ok("image_message_event"),
+        "file" => Ok("file_message_event"),
+        "video" => Ok("video_message_event"),
+        "audio" => Ok("audio_message_event"),
+        "location" => Ok("location_message_event"),
+        "room" => Ok("matrix_room"),
+        "user" => Ok("matrix_user"),
+        _ => Err(QueryError::UnknownType(value.to_string())),
+    }
+}
+
+/// Parses the query language of the `query` parameter.
+///
+/// Supported are `room:`, `space:`, `member:`, `from:`, `before:`, `after:` and `type:` filters,
+/// quoted phrases, `-exclusions` and `OR` between terms.
+pub fn parse(input: &str) -> Result<SearchQuery, QueryError> {
+    let mut query = SearchQuery::default();
+    let mut clause = Vec::new();
+    let mut pending_or = false;
+
+    for token in tokenize(input)? {
+        if let Some(field) = token.field {
+            if token.negated {
+                return Err(QueryError::ExcludedFilter(field));
+            }
+            if token.value.is_empty() {
+                return Err(QueryError::MissingValue(field));
+            }
+            match field.as_str() {
+                "room" => query.rooms.push(token.value),
+                "space" => query.spaces.push(token.value),
+                "member" => query.members.push(token.value),
+                "from" => query.senders.push(token.value),
+                "before" => query.before = Some(parse_date(&token.value)?),
+                "after" => query.after = Some(parse_date(&token.value)?),
+                _ => query.types.push(parse_type(&token.value)?),
+            }
+            continue;
+        }
+
+        if token.value.is_empty() {
+            continue;
+        }
+        if token.value == "OR" && !token.quoted && !token.negated {
+            if clause.is_empty() || pending_or {
+                return Err(QueryError::DanglingOr);
+            }
+            pending_or = true;
+            continue;
+        }
+
+        let term = if token.quoted {
+            Term::Phrase(token.value)
+        } else {
+            Term::Word(token.value)
+        };
+        if token.negated {
+            query.excluded.push(term);
+            continue;
+        }
+
+        if pending_or {
+            query.clauses.push(std::mem::take(&mut clause));
+            pending_or = false;
+        }
+        clause.push(term);
+    }
+
+    if pending_or {
+        return Err(QueryError::DanglingOr);
+    }
+    if !clause.is_empty() {
+        query.clauses.push(clause);
+    }
+    if query.clauses.is_empty()
+        && query.rooms.is_empty()
+        && query.spaces.is_empty()
+        && query.members.is_empty()
+        && query.senders.is_empty()
+    {
+        return Err(QueryError::Empty);
+    }
+
+    Ok(query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(text: &str) -> Term {
+        Term::Word(text.to_string())
+    }
+
+    #[test]
+    fn terms_and_phrases() {
+        let query = parse(r#"hello "big world" https://example.org"#).expect("Valid query");
+        assert_eq!(
+            query.clauses,
+            vec![vec![
+                word("hello"),
+                Term::Phrase("big world".to_string()),
+                word("https://example.org"),
+            ]]
+        );
+    }
+
+    #[test]
+    fn or_splits_clauses() {
+        let query = parse("a b OR c OR d").expect("Valid query");
+        assert_eq!(
+            query.clauses,
+            vec![vec![word("a"), word("b")], vec![word("c")], vec![word("d")]]
+        );
+
+        // Only a bare uppercase OR is an operator
+        let query = parse(r#"a or "OR""#).expect("Valid query");
+        assert_eq!(
+            query.clauses,
+            vec![vec![word("a"), word("or"), Term::Phrase("OR".to_string())]]
+        );
+    }
+
+    #[test]
+    fn dangling_or() {
+        assert_eq!(parse("OR a"), Err(QueryError::DanglingOr));
+        assert_eq!(parse("a OR"), Err(QueryError::DanglingOr));
+        assert_eq!(parse("a OR OR b"), Err(QueryError::DanglingOr));
+    }
+
+    #[test]
+    fn negation() {
+        let query = parse(r#"a -b -"c d" -"#).expect("Valid query");
+        assert_eq!(query.clauses, vec![vec![word("a")]]);
+        assert_eq!(
+            query.excluded,
+            vec![word("b"), Term::Phrase("c d".to_string())]
+        );
+
+        // Exclusions alone match nothing to exclude from
+        assert_eq!(parse("-a"), Err(QueryError::Empty));
+        assert_eq!(
+            parse("a -room:!x:example.org"),
+            Err(QueryError::ExcludedFilter("room".to_string()))
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let query = parse(
+            "room:!a:example.org space:#s:example.org member:@m:example.org \
+             from:@f:example.org type:image type:room before:2023-03-01 after:1970-01-02",
+        )
+        .expect("Valid query");
+        assert!(query.clauses.is_empty());
+        assert_eq!(query.rooms, vec!["!a:example.org"]);
+        assert_eq!(query.spaces, vec!["#s:example.org"]);
+        assert_eq!(query.members, vec!["@m:example.org"]);
+        assert_eq!(query.senders, vec!["@f:example.org"]);
+        assert_eq!(query.types, vec!["image_message_event", "matrix_room"]);
+        assert_eq!(query.before, Some(1_677_628_800_000));
+        assert_eq!(query.after, Some(86_400_000));
+    }
+
+    #[test]
+    fn filter_errors() {
+        assert_eq!(parse(""), Err(QueryError::Empty));
+        assert_eq!(parse("type:text"), Err(QueryError::Empty));
+        assert_eq!(
+            parse("a room:"),
+            Err(QueryError::MissingValue("room".to_string()))
+        );
+        assert_eq!(
+            parse("a type:gif"),
+            Err(QueryError::UnknownType("gif".to_string()))
+        );
+        assert_eq!(
+            parse("a after:yesterday"),
+            Err(QueryError::InvalidDate("yesterday".to_string()))
+        );
+        assert_eq!(parse(r#"a "b"#), Err(QueryError::UnterminatedQuote));
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("1970-01-01"), Ok(0));
+        assert_eq!(parse_date("2000-02-29"), Ok(951_782_400_000));
+        assert_eq!(parse_date("2024-12-31"), Ok(1_735_603_200_000));
+
+        for invalid in [
+            "2023-13-01",
+            "2023-00-10",
+            "2023-01-32",
+            "2023-01",
+            "1969-12-31",
+            "a-b-c",
+        ] {
+            assert_eq!(
+                parse_date(invalid),
+                Err(QueryError::InvalidDate(invalid.to_string()))
+            );
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use color_eyre::Result;
+use tracing::{debug, error, info};
+use utils::{
+    indradb::{util::extract_edges, AllEdgeQuery},
+    indradb_proto::Client,
+};
+use uuid::Uuid;
+
+use crate::{
+    algos::{GraphBuilder, PageRank},
+    AppState,
+};
+
+/// How often the `PageRank` of the whole graph gets recomputed.
+#[allow(clippy::duration_suboptimal_units)]
+const RANK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How strongly the graph rank influences the text score.
+const PAGERANK_WEIGHT: f32 = 0.3;
+/// Score of the most related vertex relative to the best text match.
+const RELATED_WEIGHT: f32 = 0.5;
+/// How strongly reactions to an event influence its score.
+const REACTION_WEIGHT: f32 = 0.2;
+
+/// The latest `PageRank` results of the graph.
+#[derive(Debug, Default)]
+pub struct Ranks {
+    pagerank: Option<Arc<PageRank<Uuid>>>,
+}
+
+impl Ranks {
+    /// Blends the rank of a vertex into its text score.
+    ///
+    /// Ranks are normalized by the node count so an average vertex has a rank of 1 and leaves
+    /// the text score mostly untouched while well connected vertices get boosted.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn blend(&self, uuid: &Uuid, text_score: f32) -> f32 {
+        let Some(pagerank) = &self.pagerank else {
+            return text_score;
+        };
+        let Some(rank) = pagerank.rank(uuid) else {
+            return text_score;
+        };
+        let normalized = rank * pagerank.len() as f32;
+        text_score * PAGERANK_WEIGHT.mul_add(normalized.ln_1p(), 1.0)
+    }
+}
+
+/// Boosts the score of an event by the amount of reactions it got.
+///
+/// The boost grows logarithmically so a handful of reactions matter but a flood of them can't
+/// outweigh the text score.
+#[allow(clippy::cast_precision_loss)]
+pub fn boost_reactions(score: f32, reaction_count: u64) -> f32 {
+    score * REACTION_WEIGHT.mul_add((reaction_count as f32).ln_1p(), 1.0)
+}
+
+/// Builds the adjacency map from all edges in indradb and runs `PageRank` on it.
+async fn compute_ranks(client: &mut Client) -> Result<Ranks> {
+    let output = client.get(AllEdgeQuery).await?;
+    let edges = extract_edges(output).unwrap_or_default();
+    if edges.is_empty() {
+        return Ok(Ranks::default());
+    }
+
+    let threads = std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get);
+    let pagerank = tokio::task::spawn_blocking(move || {
+        let mut graph = GraphBuilder::new();
+        for edge in edges {
+            graph.add_edge(edge.outbound_id, edge.inbound_id);
+        }
+        let graph = graph.build();
+        info!("Computing PageRank for {} vertices", graph.len());
+
+        let mut pagerank = PageRank::new(graph).with_threads(threads);
+        pagerank.compute();
+        pagerank
+    })
+    .await?;
+    debug!("Top ranked vertices: {:?}", pagerank.top_n(10));
+
+    Ok(Ranks {
+        pagerank: Some(Arc::new(pagerank)),
+    })
+}
+
+/// Runs a personalized `PageRank` seeded with the scored text matches.
+///
+/// Returns the `n` vertices closest to the matches in the graph, such as replies or the rooms
+/// they were sent in, with scores on the same scale as the seeds.
+pub async fn related(
+    state: &AppState,
+    seeds: &[(Uuid, f32)],
+    n: usize,
+) -> Result<Vec<(Uuid, f32)>> {
+    let Some(pagerank) = state.ranks.read().await.pagerank.clone() else {
+        return Ok(Vec::new());
+    };
+    if seeds.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let personalization: HashMap<Uuid, f32> = seeds.iter().copied().collect();
+    let ranks =
+        tokio::task::spawn_blocking(move || pagerank.compute_personalized(&personalization, n))
+            .await?;
+
+    let max_seed = seeds.iter().map(|(_, score)| *score).fold(0.0, f32::max);
+    let Some(&(_, max_rank)) = ranks.first() else {
+        return Ok(Vec::new());
+    };
+    if max_rank <= 0.0 {
+        return Ok(Vec::new());
+    }
+
+    Ok(ranks
+        .into_iter()
+        .map(|(uuid, rank)| (uuid, RELATED_WEIGHT * max_seed * rank / max_rank))
+        .collect())
+}
+
+/// Periodically recomputes the ranks and swaps them into the shared state.
+pub fn spawn_rank_job(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RANK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut client = state.indradb.clone();
+            match compute_ranks(&mut client).await {
+                Ok(ranks) => *state.ranks.write().await = ranks,
+                Err(e) => error!("Failed to compute PageRank: {:?}", e),
+            }
+        }
+    });
+}
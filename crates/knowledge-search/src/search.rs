@@ -0,0 +1,729 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use color_eyre::Result;
+use serde::Serialize;
+use tracing::{debug, error};
+use utils::{
+    ids,
+    indradb::{
+        util::{
+            extract_edge_properties, extract_edges, extract_vertex_properties, extract_vertices,
+        },
+        Identifier, Json, QueryExt, SpecificVertexQuery, VertexProperties,
+        VertexWithPropertyPresenceQuery, VertexWithPropertyValueQuery,
+    },
+    indradb_proto::Client,
+    text_index::{tokenize, TextIndex},
+};
+use uuid::Uuid;
+
+use crate::{
+    query::{SearchQuery, Term},
+    ranking, AppState,
+};
+
+/// Number of hits returned if the client did not ask for a specific amount.
+pub const DEFAULT_LIMIT: usize = 50;
+/// The most hits a client may ask for at once.
+pub const MAX_LIMIT: usize = 500;
+/// How many candidates per requested hit are fetched at once to check the filters needing
+/// their properties.
+const CANDIDATE_FACTOR: usize = 4;
+/// The most events the room and sender filters are resolved to, each.
+const MAX_FILTER_VERTICES: u32 = 100_000;
+
+const ROOM_TYPE: &str = "matrix_room";
+const ROOM_NAME_CHANGE_TYPE: &str = "room_name_change";
+const NAME_CHANGE_OF_TYPE: &str = "name_change_of";
+const SPACE_CONTAINS_TYPE: &str = "space_contains";
+const MEMBER_OF_TYPE: &str = "member_of";
+/// How deep nested spaces are followed when resolving a `space:` filter.
+const MAX_SPACE_DEPTH: usize = 5;
+const EVENT_IN_ROOM_TYPE: &str = "event_in_room";
+const SENT_BY_TYPE: &str = "sent_by";
+const REPLY_TO_TYPE: &str = "reply_to";
+const IN_THREAD_TYPE: &str = "in_thread";
+const REVISION_OF_TYPE: &str = "revision_of";
+/// Images whose hashes differ in at most this many of their 64 bits count as similar.
+pub const DEFAULT_MAX_IMAGE_DISTANCE: u8 = 10;
+/// How often the text index is checked for changes written by the indexer.
+const TEXT_INDEX_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize)]
+#[allow(clippy::struct_field_names)]
+pub struct Room {
+    pub uuid: Uuid,
+    pub room_id: Option<String>,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub canonical_alias: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+/// An event a hit is related to, like the event it replies to.
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkedEvent {
+    pub uuid: Uuid,
+    pub event_id: Option<String>,
+    pub text_message_body: Option<String>,
+}
+
+/// A version of an edited event's content.
+#[derive(Debug, Clone, Serialize)]
+pub struct Revision {
+    pub event_id: Option<String>,
+    pub text_message_body: Option<String>,
+    pub origin_server_ts: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Hit {
+    pub uuid: Uuid,
+    pub vertex_type: String,
+    pub event_id: Option<String>,
+    pub text_message_body: Option<String>,
+    pub properties: BTreeMap<String, serde_json::Value>,
+    pub room: Option<Room>,
+    pub in_reply_to: Option<LinkedEvent>,
+    pub thread_root: Option<LinkedEvent>,
+    /// The edit history, oldest first. Empty if the event was never edited.
+    pub revisions: Vec<Revision>,
+    pub score: f32,
+}
+
+fn properties_map(vertex_properties: &VertexProperties) -> BTreeMap<String, serde_json::Value> {
+    vertex_properties
+        .props
+        .iter()
+        .map(|prop| (prop.name.as_str().to_string(), (*prop.value).clone()))
+        .collect()
+}
+
+fn string_property(properties: &BTreeMap<String, serde_json::Value>, name: &str) -> Option<String> {
+    properties
+        .get(name)
+        .and_then(serde_json::Value::as_str)
+        .map(ToString::to_string)
+}
+
+fn room_from_properties(vertex_properties: &VertexProperties) -> Room {
+    let properties = properties_map(vertex_properties);
+    Room {
+        uuid: vertex_properties.vertex.id,
+        room_id: string_property(&properties, "room_id"),
+        name: string_property(&properties, "room_name"),
+        topic: string_property(&properties, "room_topic"),
+        canonical_alias: string_property(&properties, "room_canonical_alias"),
+        avatar_url: string_property(&properties, "room_avatar_url"),
+    }
+}
+
+/// Follows an outbound edge of the given type and returns the vertex it points at.
+async fn linked_vertex(
+    client: &mut Client,
+    uuid: Uuid,
+    edge_type: &str,
+) -> Result<Option<VertexProperties>> {
+    let query = SpecificVertexQuery::single(uuid)
+        .outbound()?
+        .t(Identifier::new(edge_type)?)
+        .inbound()?
+        .properties()?;
+    let output = client.get(query).await?;
+
+    Ok(extract_vertex_properties(output)
+        .unwrap_or_default()
+        .into_iter()
+        .next())
+}
+
+/// Follows an edge between two events like `reply_to`.
+async fn linked_event(
+    client: &mut Client,
+    event_uuid: Uuid,
+    edge_type: &str,
+) -> Result<Option<LinkedEvent>> {
+    Ok(linked_vertex(client, event_uuid, edge_type)
+        .await?
+        .map(|vertex_properties| {
+            let properties = properties_map(&vertex_properties);
+            LinkedEvent {
+                uuid: vertex_properties.vertex.id,
+                event_id: string_property(&properties, "event_id"),
+                text_message_body: string_property(&properties, "text_message_body"),
+            }
+        }))
+}
+
+/// Collects all revisions of an edited event ordered by the time they were sent.
+async fn revisions(client: &mut Client, event_uuid: Uuid) -> Result<Vec<Revision>> {
+    let query = SpecificVertexQuery::single(event_uuid)
+        .inbound()?
+        .t(Identifier::new(REVISION_OF_TYPE)?)
+        .outbound()?
+        .properties()?;
+    let output = client.get(query).await?;
+
+    let mut revisions: Vec<Revision> = extract_vertex_properties(output)
+        .unwrap_or_default()
+        .iter()
+        .map(|vertex_properties| {
+            let properties = properties_map(vertex_properties);
+            Revision {
+                event_id: string_property(&properties, "revision_event_id"),
+                text_message_body: string_property(&properties, "text_message_body"),
+                origin_server_ts: properties
+                    .get("origin_server_ts")
+                    .and_then(serde_json::Value::as_u64),
+            }
+        })
+        .collect();
+    revisions.sort_by_key(|revision| revision.origin_server_ts);
+    Ok(revisions)
+}
+
+/// Turns the properties of a matched vertex into a [`Hit`] including its linked room and the
+/// conversation it is part of.
+pub async fn hydrate(
+    client: &mut Client,
+    vertex_properties: &VertexProperties,
+    score: f32,
+) -> Result<Hit> {
+    let properties = properties_map(vertex_properties);
+    let vertex = &vertex_properties.vertex;
+    let room = if vertex.t.as_str() == ROOM_TYPE {
+        Some(room_from_properties(vertex_properties))
+    } else {
+        linked_vertex(client, vertex.id, EVENT_IN_ROOM_TYPE)
+            .await?
+            .as_ref()
+            .map(room_from_properties)
+    };
+
+    Ok(Hit {
+        uuid: vertex.id,
+        vertex_type: vertex.t.as_str().to_string(),
+        event_id: string_property(&properties, "event_id"),
+        text_message_body: string_property(&properties, "text_message_body"),
+        properties,
+        room,
+        in_reply_to: linked_event(client, vertex.id, REPLY_TO_TYPE).await?,
+        thread_root: linked_event(client, vertex.id, IN_THREAD_TYPE).await?,
+        revisions: revisions(client, vertex.id).await?,
+        score,
+    })
+}
+
+/// Periodically picks up the changes the indexer wrote to the text index.
+pub fn spawn_text_index_job(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TEXT_INDEX_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = update_text_index(&state).await {
+                error!("Failed to update the text index: {:?}", e);
+            }
+        }
+    });
+}
+
+/// Reads the files on the blocking pool so searches only wait for the update to be applied.
+async fn update_text_index(state: &AppState) -> Result<()> {
+    let position = state.text_index.read().await.position();
+    let update = tokio::task::spawn_blocking(move || position.read_update()).await??;
+    if state.text_index.write().await.apply_update(update) {
+        debug!("Updated the text index");
+    }
+    Ok(())
+}
+
+/// Collects the text matches of all `OR` clauses. A vertex has to contain every term of a
+/// clause to match it.
+///
+/// Vertices outside of `allowed` are skipped while scoring, see [`allowed_vertices`].
+fn text_candidates(
+    text_index: &TextIndex,
+    query: &SearchQuery,
+    allowed: Option<&HashSet<Uuid>>,
+) -> Vec<(Uuid, f32)> {
+    let mut candidates: HashMap<Uuid, f32> = HashMap::new();
+    for clause in &query.clauses {
+        let terms: Vec<String> = clause
+            .iter()
+            .flat_map(|term| tokenize(term.text()))
+            .collect();
+        let hits = text_index.search_all(&terms.join(" "), usize::MAX, |uuid| {
+            allowed.is_none_or(|allowed| allowed.contains(uuid))
+        });
+        for (uuid, score) in hits {
+            let best = candidates.entry(uuid).or_default();
+            *best = best.max(score);
+        }
+    }
+    candidates.into_iter().collect()
+}
+
+/// Looks up the room vertices a `room:` filter refers to by room id, alias or any name the room
+/// had.
+async fn room_uuids(client: &mut Client, rooms: &[String]) -> Result<HashSet<Uuid>> {
+    let mut uuids = HashSet::new();
+    let mut name_changes = Vec::new();
+    for room in rooms {
+        for property in ["room_id", "room_canonical_alias", "room_name"] {
+            let query = VertexWithPropertyValueQuery::new(
+                Identifier::new(property)?,
+                Json::new(serde_json::Value::String(room.clone())),
+            );
+            let output = client.get(query).await?;
+            for vertex in extract_vertices(output).unwrap_or_default() {
+                if vertex.t.as_str() == ROOM_NAME_CHANGE_TYPE {
+                    name_changes.push(vertex.id);
+                } else {
+                    uuids.insert(vertex.id);
+                }
+            }
+        }
+    }
+
+    // Former names of a room live on their own vertices pointing at the room
+    if !name_changes.is_empty() {
+        let query = SpecificVertexQuery::new(name_changes)
+            .outbound()?
+            .t(Identifier::new(NAME_CHANGE_OF_TYPE)?)
+            .inbound()?;
+        let output = client.get(query).await?;
+        uuids.extend(
+            extract_vertices(output)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|vertex| vertex.id),
+        );
+    }
+    Ok(uuids)
+}
+
+/// Resolves `space:` filters to the spaces and all rooms in them, following nested spaces.
+async fn space_rooms(client: &mut Client, spaces: &[String]) -> Result<HashSet<Uuid>> {
+    let mut rooms = room_uuids(client, spaces).await?;
+    let mut frontier: Vec<Uuid> = rooms.iter().copied().collect();
+    for _ in 0..MAX_SPACE_DEPTH {
+        if frontier.is_empty() {
+            break;
+        }
+        let query = SpecificVertexQuery::new(frontier)
+            .outbound()?
+            .t(Identifier::new(SPACE_CONTAINS_TYPE)?)
+            .inbound()?;
+        let output = client.get(query).await?;
+        frontier = extract_vertices(output)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|vertex| vertex.id)
+            .filter(|uuid| rooms.insert(*uuid))
+            .collect();
+    }
+    Ok(rooms)
+}
+
+/// Resolves `member:` filters to the rooms all of the given users joined.
+async fn member_rooms(client: &mut Client, members: &[String]) -> Result<HashSet<Uuid>> {
+    let mut rooms: Option<HashSet<Uuid>> = None;
+    for member in members {
+        let query = VertexWithPropertyValueQuery::new(
+            Identifier::new("user_id")?,
+            Json::new(serde_json::Value::String(member.clone())),
+        )
+        .outbound()?
+        .t(Identifier::new(MEMBER_OF_TYPE)?)
+        .properties()?;
+        let output = client.get(query).await?;
+        let joined: HashSet<Uuid> = extract_edge_properties(output)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|edge_properties| {
+                edge_properties.props.iter().any(|prop| {
+                    prop.name.as_str() == "membership" && prop.value.as_str() == Some("join")
+                })
+            })
+            .map(|edge_properties| edge_properties.edge.inbound_id)
+            .collect();
+
+        rooms = Some(match rooms {
+            Some(rooms) => rooms.intersection(&joined).copied().collect(),
+            None => joined,
+        });
+    }
+    Ok(rooms.unwrap_or_default())
+}
+
+/// Resolves the `room:`, `space:`, `member:` and `from:` filters to the vertices they allow:
+/// the matching rooms and the events sent in them by the given senders. `None` if the query has
+/// none of these filters.
+///
+/// The events of the rooms and of each sender are looked up with at most
+/// [`MAX_FILTER_VERTICES`] results each.
+async fn allowed_vertices(
+    client: &mut Client,
+    query: &SearchQuery,
+    rooms: &HashSet<Uuid>,
+    member_rooms: &HashSet<Uuid>,
+) -> Result<Option<HashSet<Uuid>>> {
+    let has_room_filter = !query.rooms.is_empty() || !query.spaces.is_empty();
+    let candidate_rooms: Option<HashSet<Uuid>> = if !query.members.is_empty() {
+        Some(if has_room_filter {
+            rooms.intersection(member_rooms).copied().collect()
+        } else {
+            member_rooms.clone()
+        })
+    } else if has_room_filter {
+        Some(rooms.clone())
+    } else {
+        None
+    };
+
+    let in_rooms = match candidate_rooms {
+        Some(mut candidate_rooms) if !candidate_rooms.is_empty() => {
+            let output = client
+                .get(
+                    SpecificVertexQuery::new(candidate_rooms.iter().copied().collect())
+                        .inbound()?
+                        .t(Identifier::new(EVENT_IN_ROOM_TYPE)?)
+                        .limit(MAX_FILTER_VERTICES),
+                )
+                .await?;
+            candidate_rooms.extend(
+                extract_edges(output)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|edge| edge.outbound_id),
+            );
+            Some(candidate_rooms)
+        }
+        candidate_rooms => candidate_rooms,
+    };
+    if query.senders.is_empty() {
+        return Ok(in_rooms);
+    }
+
+    let mut sent = HashSet::new();
+    for sender in &query.senders {
+        let output = client
+            .get(
+                VertexWithPropertyValueQuery::new(
+                    Identifier::new("user_id")?,
+                    Json::new(serde_json::Value::String(sender.clone())),
+                )
+                .inbound()?
+                .t(Identifier::new(SENT_BY_TYPE)?)
+                .limit(MAX_FILTER_VERTICES),
+            )
+            .await?;
+        sent.extend(
+            extract_edges(output)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|edge| edge.outbound_id),
+        );
+    }
+    Ok(Some(match in_rooms {
+        Some(in_rooms) => in_rooms.intersection(&sent).copied().collect(),
+        None => sent,
+    }))
+}
+
+/// The searchable text of a vertex as normalized terms separated by single spaces.
+fn normalized_text(properties: &BTreeMap<String, serde_json::Value>) -> String {
+    let mut terms = Vec::new();
+    for property in [
+        "text_message_body",
+        "media_filename",
+        "attachment_text",
+        "room_name",
+        "room_topic",
+        "room_canonical_alias",
+        "user_id",
+        "user_display_name",
+    ] {
+        if let Some(text) = properties.get(property).and_then(serde_json::Value::as_str) {
+            terms.extend(tokenize(text));
+        }
+    }
+    if let Some(keys) = properties
+        .get("reaction_keys")
+        .and_then(serde_json::Value::as_array)
+    {
+        terms.extend(
+            keys.iter()
+                .filter_map(serde_json::Value::as_str)
+                .flat_map(tokenize),
+        );
+    }
+    format!(" {} ", terms.join(" "))
+}
+
+fn contains_term(text: &str, term: &Term) -> bool {
+    let terms: Vec<String> = tokenize(term.text()).collect();
+    terms.is_empty() || text.contains(&format!(" {} ", terms.join(" ")))
+}
+
+/// Checks the filters of the query which need the properties of a vertex. The room and sender
+/// filters were applied to the candidates already.
+///
+/// Phrases are only checked for vertices which came from the text index. Related vertices don't
+/// need to contain the query.
+fn matches(query: &SearchQuery, vertex_properties: &VertexProperties, text_match: bool) -> bool {
+    if !query.types.is_empty() && !query.types.contains(&vertex_properties.vertex.t.as_str()) {
+        return false;
+    }
+    let properties = properties_map(vertex_properties);
+    if query.before.is_some() || query.after.is_some() {
+        let Some(timestamp) = properties
+            .get("origin_server_ts")
+            .and_then(serde_json::Value::as_u64)
+        else {
+            return false;
+        };
+        if query.before.is_some_and(|before| timestamp >= before)
+            || query.after.is_some_and(|after| timestamp < after)
+        {
+            return false;
+        }
+    }
+
+    let text = normalized_text(&properties);
+    if query.excluded.iter().any(|term| contains_term(&text, term)) {
+        return false;
+    }
+    if text_match && !query.clauses.is_empty() {
+        return query.clauses.iter().any(|clause| {
+            clause
+                .iter()
+                .filter(|term| matches!(term, Term::Phrase(_)))
+                .all(|phrase| contains_term(&text, phrase))
+        });
+    }
+    true
+}
+
+/// Executes a parsed query.
+///
+/// Free text is looked up in the full-text index while queries consisting only of filters are
+/// looked up in indradb. Hits are ordered by their BM25 score blended with the `PageRank` of the
+/// vertex and boosted by their reactions. Vertices closely related to the text matches are
+/// included as well.
+///
+/// The room and sender filters limit the candidates before they are ranked. The remaining
+/// filters need the properties of the candidates, which are fetched in batches of the best
+/// ranked ones until enough hits passed.
+pub async fn search(state: &AppState, query: &SearchQuery, limit: usize) -> Result<Vec<Hit>> {
+    let candidate_limit = limit.saturating_mul(CANDIDATE_FACTOR).max(1);
+    let mut client = state.indradb.clone();
+
+    let mut rooms = if query.rooms.is_empty() {
+        HashSet::new()
+    } else {
+        room_uuids(&mut client, &query.rooms).await?
+    };
+    if !query.spaces.is_empty() {
+        rooms.extend(space_rooms(&mut client, &query.spaces).await?);
+    }
+    let member_rooms = if query.members.is_empty() {
+        HashSet::new()
+    } else {
+        member_rooms(&mut client, &query.members).await?
+    };
+    let allowed = allowed_vertices(&mut client, query, &rooms, &member_rooms).await?;
+
+    let mut scored = if query.clauses.is_empty() {
+        // Queries without free text always have a room or sender filter
+        allowed.iter().flatten().map(|uuid| (*uuid, 1.0)).collect()
+    } else {
+        let text_index = state.text_index.read().await;
+        text_candidates(&text_index, query, allowed.as_ref())
+    };
+    let text_matches: HashSet<Uuid> = if query.clauses.is_empty() {
+        HashSet::new()
+    } else {
+        scored.iter().map(|(uuid, _)| *uuid).collect()
+    };
+    {
+        let ranks = state.ranks.read().await;
+        for (uuid, score) in &mut scored {
+            *score = ranks.blend(uuid, *score);
+        }
+    }
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    if !query.clauses.is_empty() {
+        // Pull in graph neighbours of the best text matches which might not contain the query
+        // themselves
+        let seeds = &scored[..scored.len().min(candidate_limit)];
+        let related = ranking::related(state, seeds, limit).await?;
+        let mut combined: HashMap<Uuid, f32> = scored.into_iter().collect();
+        for (uuid, score) in related {
+            if allowed
+                .as_ref()
+                .is_none_or(|allowed| allowed.contains(&uuid))
+            {
+                *combined.entry(uuid).or_default() += score;
+            }
+        }
+        scored = combined.into_iter().collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    }
+
+    let mut hits = Vec::new();
+    for batch in scored.chunks(candidate_limit) {
+        if hits.len() >= limit {
+            break;
+        }
+        let remaining = limit - hits.len();
+        hits.extend(batch_hits(&mut client, query, batch, &text_matches, remaining).await?);
+    }
+
+    Ok(hits)
+}
+
+/// Checks the filters for a batch of ranked candidates and hydrates at most `limit` of the ones
+/// which passed, best first.
+async fn batch_hits(
+    client: &mut Client,
+    query: &SearchQuery,
+    batch: &[(Uuid, f32)],
+    text_matches: &HashSet<Uuid>,
+    limit: usize,
+) -> Result<Vec<Hit>> {
+    let output = client
+        .get(SpecificVertexQuery::new(batch.iter().map(|(uuid, _)| *uuid).collect()).properties()?)
+        .await?;
+    let mut vertices: HashMap<Uuid, VertexProperties> = extract_vertex_properties(output)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|vertex_properties| (vertex_properties.vertex.id, vertex_properties))
+        .collect();
+
+    // The text index may know about vertices which were not written to indradb yet.
+    let mut batch: Vec<(Uuid, f32)> = batch
+        .iter()
+        .filter(|(uuid, _)| {
+            vertices.get(uuid).is_some_and(|vertex_properties| {
+                matches(query, vertex_properties, text_matches.contains(uuid))
+            })
+        })
+        .copied()
+        .collect();
+    for (uuid, score) in &mut batch {
+        let reaction_count = vertices.get(uuid).and_then(|vertex_properties| {
+            vertex_properties
+                .props
+                .iter()
+                .find(|prop| prop.name.as_str() == "reaction_count")
+                .and_then(|prop| prop.value.as_u64())
+        });
+        if let Some(reaction_count) = reaction_count {
+            *score = ranking::boost_reactions(*score, reaction_count);
+        }
+    }
+    batch.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut hits = Vec::new();
+    for (uuid, score) in batch {
+        if hits.len() >= limit {
+            break;
+        }
+        if let Some(vertex_properties) = vertices.remove(&uuid) {
+            hits.push(hydrate(client, &vertex_properties, score).await?);
+        }
+    }
+    Ok(hits)
+}
+
+fn image_hash(vertex_properties: &VertexProperties) -> Option<u64> {
+    vertex_properties
+        .props
+        .iter()
+        .find(|prop| prop.name.as_str() == "image_hash")
+        .and_then(|prop| prop.value.as_str())
+        .and_then(|hash| u64::from_str_radix(hash, 16).ok())
+}
+
+/// Finds images which look like the image sent in the event with the given id.
+///
+/// Images are compared by the Hamming distance of their perceptual hashes. Hits are ordered from
+/// most to least similar and scored from 1 for identical hashes down to 0. Returns `None` if the
+/// event is unknown or is no hashed image.
+pub async fn similar_images(
+    state: &AppState,
+    event_id: &str,
+    max_distance: u8,
+    limit: usize,
+) -> Result<Option<Vec<Hit>>> {
+    let mut client = state.indradb.clone();
+    let image_hash_type = Identifier::new("image_hash")?;
+
+    let output = client
+        .get(
+            SpecificVertexQuery::single(ids::event_uuid(event_id))
+                .properties()?
+                .name(image_hash_type),
+        )
+        .await?;
+    let Some((source, source_hash)) = extract_vertex_properties(output)
+        .unwrap_or_default()
+        .iter()
+        .find_map(|vertex_properties| {
+            Some((vertex_properties.vertex.id, image_hash(vertex_properties)?))
+        })
+    else {
+        return Ok(None);
+    };
+
+    let output = client
+        .get(
+            VertexWithPropertyPresenceQuery::new(image_hash_type)
+                .properties()?
+                .name(image_hash_type),
+        )
+        .await?;
+    let mut similar: Vec<(Uuid, u8)> = extract_vertex_properties(output)
+        .unwrap_or_default()
+        .iter()
+        .filter(|vertex_properties| vertex_properties.vertex.id != source)
+        .filter_map(|vertex_properties| {
+            let hash = image_hash(vertex_properties)?;
+            let distance = u8::try_from((hash ^ source_hash).count_ones()).ok()?;
+            (distance <= max_distance).then_some((vertex_properties.vertex.id, distance))
+        })
+        .collect();
+    similar.sort_by_key(|(_, distance)| *distance);
+    similar.truncate(limit);
+    if similar.is_empty() {
+        return Ok(Some(Vec::new()));
+    }
+
+    let output = client
+        .get(
+            SpecificVertexQuery::new(similar.iter().map(|(uuid, _)| *uuid).collect())
+                .properties()?,
+        )
+        .await?;
+    let mut vertices: HashMap<Uuid, VertexProperties> = extract_vertex_properties(output)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|vertex_properties| (vertex_properties.vertex.id, vertex_properties))
+        .collect();
+
+    let mut hits = Vec::with_capacity(similar.len());
+    for (uuid, distance) in similar {
+        let Some(vertex_properties) = vertices.remove(&uuid) else {
+            continue;
+        };
+        let score = 1.0 - f32::from(distance) / 64.0;
+        hits.push(hydrate(&mut client, &vertex_properties, score).await?);
+    }
+    Ok(Some(hits))
+}
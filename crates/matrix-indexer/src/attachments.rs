@@ -0,0 +1,138 @@
+use color_eyre::{eyre::eyre, Result};
+use image::imageops::FilterType;
+use matrix_sdk::ruma::MxcUri;
+use url::Url;
+
+/// Edge length of the thumbnails requested for image hashing. The hash only needs 9x8 pixels.
+const THUMBNAIL_SIZE: u32 = 96;
+
+/// File formats we are able to extract text from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachmentKind {
+    /// Plain text and markdown. Markdown is indexed as is since its markup barely affects search.
+    Text,
+    Pdf,
+}
+
+impl AttachmentKind {
+    /// Detects the format by the mimetype and falls back to the file extension as clients
+    /// often send `application/octet-stream` or no mimetype at all.
+    pub fn detect(mimetype: Option<&str>, filename: &str) -> Option<Self> {
+        let mimetype = mimetype
+            .and_then(|mimetype| mimetype.split(';').next())
+            .map(|mimetype| mimetype.trim().to_lowercase());
+        match mimetype.as_deref() {
+            Some("application/pdf") => return Some(Self::Pdf),
+            Some("text/plain" | "text/markdown" | "text/x-markdown") => return Some(Self::Text),
+            _ => {}
+        }
+
+        let extension = filename.rsplit_once('.')?.1.to_lowercase();
+        match extension.as_str() {
+            "pdf" => Some(Self::Pdf),
+            "txt" | "text" | "md" | "markdown" => Some(Self::Text),
+            _ => None,
+        }
+    }
+}
+
+/// The URL of a media endpoint of the homeserver for `mxc_uri`.
+fn media_url(homeserver: &Url, endpoint: &str, mxc_uri: &MxcUri) -> Result<Url> {
+    let (server_name, media_id) = mxc_uri.parts()?;
+    let mut url = homeserver.clone();
+    url.path_segments_mut()
+        .map_err(|()| eyre!("The homeserver URL {} can not be a base", homeserver))?
+        .pop_if_empty()
+        .extend([
+            "_matrix",
+            "media",
+            "v3",
+            endpoint,
+            server_name.as_str(),
+            media_id,
+        ]);
+    Ok(url)
+}
+
+/// The URL to download the file behind `mxc_uri` from.
+pub fn download_url(homeserver: &Url, mxc_uri: &MxcUri) -> Result<Url> {
+    media_url(homeserver, "download", mxc_uri)
+}
+
+/// The URL of a small server generated thumbnail of the image behind `mxc_uri`.
+pub fn thumbnail_url(homeserver: &Url, mxc_uri: &MxcUri) -> Result<Url> {
+    let mut url = media_url(homeserver, "thumbnail", mxc_uri)?;
+    url.query_pairs_mut()
+        .append_pair("width", &THUMBNAIL_SIZE.to_string())
+        .append_pair("height", &THUMBNAIL_SIZE.to_string())
+        .append_pair("method", "scale");
+    Ok(url)
+}
+
+/// Downloads media, giving up once it turns out to be larger than `max_size`.
+///
+/// matrix-sdk reads the whole body into memory before we get to see it, so the download is done
+/// here instead. A `Content-Length` above the limit stops it before the body is read at all and
+/// a body without one is only read up to the limit. Returns `None` for media which is too large.
+pub async fn download(
+    http_client: &reqwest::Client,
+    url: Url,
+    max_size: u64,
+) -> Result<Option<Vec<u8>>> {
+    let mut response = http_client.get(url).send().await?.error_for_status()?;
+    if response
+        .content_length()
+        .is_some_and(|length| length > max_size)
+    {
+        return Ok(None);
+    }
+
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if u64::try_from(data.len() + chunk.len()).unwrap_or(u64::MAX) > max_size {
+            return Ok(None);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some(data))
+}
+
+/// Extracts the plain text of a downloaded attachment.
+///
+/// PDF parsing is CPU bound and may panic on malformed files, so it runs on the blocking pool.
+pub async fn extract_text(kind: AttachmentKind, data: Vec<u8>) -> Result<String> {
+    let text = match kind {
+        AttachmentKind::Text => String::from_utf8_lossy(&data).into_owned(),
+        AttachmentKind::Pdf => {
+            tokio::task::spawn_blocking(move || {
+                pdf_extract::extract_text_from_mem(&data).map_err(|e| eyre!("{}", e))
+            })
+            .await??
+        }
+    };
+    Ok(text.trim().to_string())
+}
+
+/// Computes the difference hash (dHash) of an image as 16 hex digits.
+///
+/// The image is shrunk to 9x8 grayscale pixels and every bit tells whether a pixel is brighter
+/// than its right neighbour. Similar images differ in only a few bits, so the Hamming distance
+/// between two hashes measures how alike they look.
+pub async fn image_hash(data: Vec<u8>) -> Result<String> {
+    tokio::task::spawn_blocking(move || -> Result<String> {
+        let pixels = image::load_from_memory(&data)?
+            .resize_exact(9, 8, FilterType::Triangle)
+            .to_luma8();
+        let mut hash = 0u64;
+        for y in 0..8 {
+            for x in 0..8 {
+                hash <<= 1;
+                if pixels.get_pixel(x, y)[0] > pixels.get_pixel(x + 1, y)[0] {
+                    hash |= 1;
+                }
+            }
+        }
+        Ok(format!("{hash:016x}"))
+    })
+    .await?
+}
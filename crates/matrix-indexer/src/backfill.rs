@@ -0,0 +1,66 @@
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::PathBuf};
+
+use color_eyre::Result;
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use serde::{Deserialize, Serialize};
+
+/// Where the backfill progress is stored.
+pub const CHECKPOINTS_PATH: &str = "./backfill.json";
+/// How many events are requested per `/messages` page.
+pub const PAGE_SIZE: u32 = 100;
+
+/// How far back joined rooms get backfilled. Without any limit rooms are backfilled up to their
+/// creation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BackfillLimits {
+    /// Maximum number of events backfilled per room.
+    pub max_events: Option<u64>,
+    /// Events older than this many days are not backfilled.
+    pub max_days: Option<u64>,
+}
+
+/// Backfill progress of a single room.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The pagination token to continue backwards from. `None` before the first page.
+    pub from: Option<String>,
+    /// How many events were backfilled so far.
+    pub events: u64,
+    /// Set once the start of the room or a limit was reached.
+    pub done: bool,
+}
+
+/// Backfill progress of all rooms, persisted after every page so interrupted backfills resume
+/// where they stopped.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoints {
+    rooms: BTreeMap<OwnedRoomId, Checkpoint>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Checkpoints {
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut checkpoints = if path.exists() {
+            let file = File::open(&path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            Checkpoints::default()
+        };
+        checkpoints.path = path;
+        Ok(checkpoints)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        utils::write_json_atomic(&self.path, self)?;
+        Ok(())
+    }
+
+    pub fn get(&self, room_id: &RoomId) -> Checkpoint {
+        self.rooms.get(room_id).cloned().unwrap_or_default()
+    }
+
+    pub fn set(&mut self, room_id: OwnedRoomId, checkpoint: Checkpoint) {
+        self.rooms.insert(room_id, checkpoint);
+    }
+}
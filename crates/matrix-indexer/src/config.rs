@@ -4,9 +4,18 @@ use kdl::{KdlDocument, KdlEntry, KdlIdentifier, KdlNode};
 use miette::{Diagnostic, NamedSource, SourceSpan};
 use thiserror::Error;
 
+use crate::backfill::BackfillLimits;
+
+/// Attachments larger than this many bytes are not downloaded unless configured otherwise.
+const DEFAULT_MAX_ATTACHMENT_SIZE: u64 = 10 * 1024 * 1024;
+
 pub struct Config {
     pub homeserver_url: String,
     pub indradb_endpoint: String,
+    /// Maximum size in bytes of files which get downloaded to extract their text. 0 disables it.
+    pub max_attachment_size: u64,
+    /// Set if joined rooms should be backfilled.
+    pub backfill: Option<BackfillLimits>,
     pub auth_data: AuthData,
 }
 
@@ -49,7 +58,7 @@ fn missing_field(source: String, snippet: &SourceSpan, help: &str) {
 }
 
 #[allow(clippy::unwrap_used)]
-#[allow(clippy::too_many_lines)]
+#[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
 pub fn load() -> Config {
     let source = std::fs::read_to_string("config.kdl")
         .expect("Unable to open config.kdl file. Is it present?");
@@ -129,6 +138,75 @@ pub fn load() -> Config {
                         unreachable!();
                     };
 
+                    let max_attachment_size_entry = config.get("max_attachment_size");
+                    let max_attachment_size = if let Some(max_attachment_size_entry) =
+                        max_attachment_size_entry
+                    {
+                        if let Some(max_attachment_size) =
+                            max_attachment_size_entry.entries().first()
+                        {
+                            if let Some(max_attachment_size) = max_attachment_size
+                                .value()
+                                .as_i64()
+                                .and_then(|size| u64::try_from(size).ok())
+                            {
+                                max_attachment_size
+                            } else {
+                                missing_field(source, max_attachment_size.span(),"\"max_attachment_size\" field has incorrect type. Make sure it is a positive number of bytes without quotes.");
+                                unreachable!();
+                            }
+                        } else {
+                            missing_field(source, max_attachment_size_entry.span(),"\"max_attachment_size\" field has no value. Make sure it is a positive number of bytes without quotes.");
+                            unreachable!();
+                        }
+                    } else {
+                        DEFAULT_MAX_ATTACHMENT_SIZE
+                    };
+
+                    let backfill_entry = config.get("backfill");
+                    let backfill = if let Some(backfill_entry) = backfill_entry {
+                        let mut limits = BackfillLimits::default();
+                        if let Some(backfill_children) = backfill_entry.children() {
+                            if let Some(max_events_entry) = backfill_children.get("max_events") {
+                                if let Some(max_events) = max_events_entry.entries().first() {
+                                    if let Some(max_events) = max_events
+                                        .value()
+                                        .as_i64()
+                                        .and_then(|max_events| u64::try_from(max_events).ok())
+                                    {
+                                        limits.max_events = Some(max_events);
+                                    } else {
+                                        missing_field(source, max_events.span(),"\"max_events\" field has incorrect type. Make sure it is a positive number without quotes.");
+                                        unreachable!();
+                                    }
+                                } else {
+                                    missing_field(source, max_events_entry.span(),"\"max_events\" field has no value. Make sure it is a positive number without quotes.");
+                                    unreachable!();
+                                }
+                            }
+                            if let Some(max_days_entry) = backfill_children.get("max_days") {
+                                if let Some(max_days) = max_days_entry.entries().first() {
+                                    if let Some(max_days) = max_days
+                                        .value()
+                                        .as_i64()
+                                        .and_then(|max_days| u64::try_from(max_days).ok())
+                                    {
+                                        limits.max_days = Some(max_days);
+                                    } else {
+                                        missing_field(source, max_days.span(),"\"max_days\" field has incorrect type. Make sure it is a positive number without quotes.");
+                                        unreachable!();
+                                    }
+                                } else {
+                                    missing_field(source, max_days_entry.span(),"\"max_days\" field has no value. Make sure it is a positive number without quotes.");
+                                    unreachable!();
+                                }
+                            }
+                        }
+                        Some(limits)
+                    } else {
+                        None
+                    };
+
                     let access_token_entry = matrix_children.get("access_token");
                     let access_token = if let Some(access_token_entry) = access_token_entry {
                         if let Some(access_token) = access_token_entry.entries().first() {
@@ -172,6 +250,8 @@ pub fn load() -> Config {
                     Config {
                         homeserver_url,
                         indradb_endpoint,
+                        max_attachment_size,
+                        backfill,
                         auth_data,
                     }
                 } else {
@@ -1,44 +1,55 @@
 use std::{
-    collections::{BTreeMap, VecDeque},
-    mem::replace,
+    collections::{BTreeMap, BTreeSet},
+    mem::{replace, take},
 };
 
-use color_eyre::Result;
-use lazy_static::lazy_static;
-use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId};
-use tokio::task::JoinHandle;
+use color_eyre::{eyre::eyre, Result};
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId};
+use tokio::{sync::oneshot, task::JoinHandle};
+use tracing::error;
 use utils::{
+    ids,
     indradb::{self, BulkInsertItem},
     indradb_proto as proto,
 };
-use uuid::{Context, Uuid};
+use uuid::Uuid;
+
+use crate::links;
 
 const REQUEST_BUFFER_SIZE: usize = 10_000;
 
-lazy_static! {
-    static ref CONTEXT: Context = Context::new(0);
-}
+/// A batch of items together with the channel its outcome gets reported on.
+type Batch = (Vec<indradb::BulkInsertItem>, oneshot::Sender<Result<()>>);
 
+/// Sends buffered items to indradb through a pool of workers.
+///
+/// Every batch is acknowledged by the worker which inserted it. [`BulkInserter::flush`] waits for
+/// all of them, so once it returned `Ok` everything pushed before is stored.
 pub struct BulkInserter {
-    requests: async_channel::Sender<Vec<indradb::BulkInsertItem>>,
-    workers: Vec<JoinHandle<Result<()>>>,
+    requests: async_channel::Sender<Batch>,
+    workers: Vec<JoinHandle<()>>,
+    /// Acknowledgements of batches handed to the workers since the last flush.
+    pending: Vec<oneshot::Receiver<Result<()>>>,
     buf: Vec<indradb::BulkInsertItem>,
     client: proto::Client,
 }
 
 impl BulkInserter {
     pub fn new(client: proto::Client) -> Self {
-        let (tx, rx) = async_channel::bounded::<Vec<indradb::BulkInsertItem>>(10);
+        let (tx, rx) = async_channel::bounded::<Batch>(10);
         let mut workers = Vec::default();
 
         for _ in 0..10 {
             let rx = rx.clone();
             let mut client = client.clone();
             workers.push(tokio::spawn(async move {
-                while let Ok(buf) = rx.recv().await {
-                    client.bulk_insert(buf).await?;
+                // A failed insert is reported to whoever flushes next. The worker itself keeps
+                // going so the pool never shrinks.
+                while let Ok((buf, ack)) = rx.recv().await {
+                    let result = client.bulk_insert(buf).await.map_err(Into::into);
+                    // Nobody waits for the result if the inserter was dropped
+                    let _ = ack.send(result);
                 }
-                Ok(())
             }));
         }
 
@@ -46,6 +57,7 @@ impl BulkInserter {
             client,
             requests: tx,
             workers,
+            pending: Vec::new(),
             buf: Vec::with_capacity(REQUEST_BUFFER_SIZE),
         }
     }
@@ -55,23 +67,59 @@ impl BulkInserter {
         Ok(())
     }
 
+    async fn send(&mut self, buf: Vec<indradb::BulkInsertItem>) -> Result<()> {
+        let (ack, acknowledged) = oneshot::channel();
+        self.requests.send((buf, ack)).await?;
+        self.pending.push(acknowledged);
+        Ok(())
+    }
+
+    /// Sends out the buffer and waits until every batch since the last flush was inserted.
+    ///
+    /// Failed batches are logged one by one and the first failure is returned. Their items are
+    /// not retried; callers are expected to process them again.
     pub async fn flush(&mut self) -> Result<()> {
         if !self.buf.is_empty() {
-            self.requests.send(self.buf.clone()).await?;
+            let buf = replace(&mut self.buf, Vec::with_capacity(REQUEST_BUFFER_SIZE));
+            self.send(buf).await?;
         }
-        //self.requests.close();
-        // for worker in &self.workers {
-        //     worker.await??;
-        // }
+
+        let pending = take(&mut self.pending);
+        let batches = pending.len();
+        let mut failures = Vec::new();
+        for acknowledged in pending {
+            let result = acknowledged
+                .await
+                .unwrap_or_else(|_| Err(eyre!("Bulk insert worker stopped before inserting")));
+            if let Err(e) = result {
+                error!("Bulk insert failed: {:?}", e);
+                failures.push(e);
+            }
+        }
+        let failed = failures.len();
+        if let Some(e) = failures.into_iter().next() {
+            return Err(e.wrap_err(format!("{failed} of {batches} bulk inserts failed")));
+        }
+
         self.sync().await?;
         Ok(())
     }
 
+    /// Flushes everything still buffered and waits for the workers to exit.
+    pub async fn shutdown(mut self) -> Result<()> {
+        let flushed = self.flush().await;
+        self.requests.close();
+        for worker in self.workers {
+            worker.await?;
+        }
+        flushed
+    }
+
     pub async fn push(&mut self, item: indradb::BulkInsertItem) -> Result<()> {
         self.buf.push(item);
         if self.buf.len() >= REQUEST_BUFFER_SIZE {
             let buf = replace(&mut self.buf, Vec::with_capacity(REQUEST_BUFFER_SIZE));
-            self.requests.send(buf).await?;
+            self.send(buf).await?;
         }
         Ok(())
     }
@@ -83,6 +131,36 @@ pub struct UUIDEventMapType {
     pub uuid: EventUuid,
     pub event_type: utils::indradb::Identifier,
     pub event_properties: EventProperties,
+    pub mentions: MentionTargets,
+    pub origin_server_ts: MilliSecondsSinceUnixEpoch,
+    pub sender: OwnedUserId,
+}
+
+/// An earlier or later version of the content of an edited event.
+#[derive(Clone)]
+pub struct UUIDRevisionMapType {
+    pub uuid: Uuid,
+    /// The event which carried this content. Either the original event or one of its edits.
+    pub event_id: OwnedEventId,
+    pub revision_of: EventUuid,
+    pub event_properties: EventProperties,
+    pub origin_server_ts: MilliSecondsSinceUnixEpoch,
+    pub sender: OwnedUserId,
+}
+
+/// An `m.replace` edit of an event.
+#[derive(Clone)]
+pub struct UUIDEditMapType {
+    /// The edit itself, not the edited event.
+    pub event_id: OwnedEventId,
+    pub original_event_id: OwnedEventId,
+    /// Where the original event has to be looked up if we don't know its sender yet.
+    pub room_id: OwnedRoomId,
+    pub event_properties: EventProperties,
+    pub mentions: MentionTargets,
+    pub origin_server_ts: MilliSecondsSinceUnixEpoch,
+    /// Only edits by the sender of the original event are applied.
+    pub sender: OwnedUserId,
 }
 
 #[derive(Clone)]
@@ -90,110 +168,847 @@ pub struct UUIDRoomMapType {
     pub room_id: OwnedRoomId,
     pub uuid: RoomUuid,
     pub room_properties: RoomProperties,
+    /// Every name the room had, so it can still be found by names people remember.
+    pub names: BTreeSet<String>,
+}
+
+/// A name a room was given at some point.
+#[derive(Clone)]
+pub struct UUIDRoomNameMapType {
+    pub uuid: Uuid,
+    pub room_uuid: RoomUuid,
+    pub name: String,
+    pub origin_server_ts: MilliSecondsSinceUnixEpoch,
+}
+
+#[derive(Clone)]
+pub struct UUIDUserMapType {
+    pub user_id: OwnedUserId,
+    pub uuid: UserUuid,
+    /// `None` if only the user id is known, which leaves a stored profile untouched.
+    pub user_properties: Option<UserProperties>,
 }
 
 #[derive(Clone)]
 pub enum EventProperties {
     TextMessage(String, Option<String>, Option<String>),
+    Media(MediaProperties),
+    /// A location with its description and `geo:` URI.
+    Location(String, String),
+    /// The content was removed by a redaction.
+    Redacted,
 }
 
-#[derive(Clone)]
+/// Metadata of an image, file, video or audio message.
+#[derive(Clone, Default)]
+pub struct MediaProperties {
+    /// The caption or, if there is none, the filename.
+    pub body: String,
+    pub filename: Option<String>,
+    pub mimetype: Option<String>,
+    /// Size in bytes.
+    pub size: Option<u64>,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    /// Duration in milliseconds.
+    pub duration: Option<u64>,
+    /// The `mxc://` URI of the media. In encrypted rooms this points at the encrypted file.
+    pub url: Option<String>,
+    /// Plain text extracted from text, markdown and PDF files.
+    pub attachment_text: Option<String>,
+    /// Perceptual hash of an image as 16 hex digits.
+    pub image_hash: Option<String>,
+}
+
+#[derive(Clone, Default, PartialEq, Eq)]
 pub struct RoomProperties {
     pub name: Option<String>,
     pub topic: Option<String>,
+    pub canonical_alias: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+/// How a room is listed in a space.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SpaceChild {
+    /// Lexicographic ordering key of the child within the space.
+    pub order: Option<String>,
+    pub suggested: bool,
+}
+
+/// The membership of a user in a room.
+#[derive(Clone)]
+pub struct Membership {
+    /// The membership state like `join` or `leave`.
+    pub state: String,
+    /// When the user last joined. Rooms we first see through the state of a sync only tell us
+    /// about the latest member event, which might be a later profile change.
+    pub joined_at: Option<MilliSecondsSinceUnixEpoch>,
+    updated_at: MilliSecondsSinceUnixEpoch,
+}
+
+/// A change of the room state from a state event. `None` means the state was removed.
+pub enum RoomStateUpdate {
+    Name(Option<String>),
+    Topic(Option<String>),
+    CanonicalAlias(Option<String>),
+    Avatar(Option<String>),
+}
+
+impl RoomStateUpdate {
+    const fn kind(&self) -> &'static str {
+        match self {
+            RoomStateUpdate::Name(_) => "name",
+            RoomStateUpdate::Topic(_) => "topic",
+            RoomStateUpdate::CanonicalAlias(_) => "canonical_alias",
+            RoomStateUpdate::Avatar(_) => "avatar",
+        }
+    }
+}
+
+/// The vertices a message refers to through mentions and permalinks.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct MentionTargets {
+    pub users: BTreeSet<UserUuid>,
+    pub rooms: BTreeSet<RoomUuid>,
+    pub events: BTreeSet<EventUuid>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct UserProperties {
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
 }
 
 pub type RoomUuid = Uuid;
 pub type EventUuid = Uuid;
+pub type UserUuid = Uuid;
+
+/// Everything preprocessed since the last push to indradb.
+#[derive(Default)]
+pub struct Changes {
+    /// Rooms which are new or whose properties changed.
+    pub rooms: BTreeMap<RoomUuid, UUIDRoomMapType>,
+    pub room_name_list: Vec<UUIDRoomNameMapType>,
+    /// Listings of rooms in spaces which are new or changed, keyed by space and child room.
+    pub space_children: BTreeMap<(RoomUuid, RoomUuid), SpaceChild>,
+    /// Rooms which were removed from a space and whose edges have to be deleted.
+    pub removed_space_children: Vec<(RoomUuid, RoomUuid)>,
+    pub memberships: BTreeMap<(UserUuid, RoomUuid), Membership>,
+    pub user_list: BTreeMap<UserUuid, UUIDUserMapType>,
+    pub message_list: BTreeMap<EventUuid, UUIDEventMapType>,
+    pub room_event_links: BTreeMap<EventUuid, RoomUuid>,
+    pub sender_links: BTreeMap<EventUuid, UserUuid>,
+    /// Events which were referenced before we saw them. Those already stored are skipped.
+    pub placeholders: BTreeMap<EventUuid, OwnedEventId>,
+    pub reply_links: BTreeMap<EventUuid, EventUuid>,
+    pub thread_links: BTreeMap<EventUuid, EventUuid>,
+    pub revision_list: Vec<UUIDRevisionMapType>,
+    /// All edits of each edited event. The newest one by the original sender replaces the
+    /// content if it is newer than the edit stored in indradb.
+    pub edits: BTreeMap<EventUuid, Vec<UUIDEditMapType>>,
+    /// Who reacted to which event with which key, by the event id of the reaction.
+    pub reactions: BTreeMap<OwnedEventId, (UserUuid, EventUuid, String)>,
+    pub redactions: BTreeSet<OwnedEventId>,
+}
 
 // TODO: Track all the properties!
+/// Turns sync responses into the changes which get pushed to indradb.
+///
+/// Events only stay in memory until the next push. Whatever later events depend on, like the
+/// newest edit or whether an event got redacted, is looked up in indradb while pushing, so
+/// memory stays flat however long the indexer runs. Only rooms, users and memberships are
+/// remembered to skip writing them again when nothing changed.
 #[derive(Default)]
 pub struct MessagesMap {
-    event_uuids: BTreeMap<OwnedEventId, EventUuid>,
-    pub message_list: VecDeque<UUIDEventMapType>,
-    room_uuids: BTreeMap<OwnedRoomId, RoomUuid>,
-    pub room_list: VecDeque<UUIDRoomMapType>,
-    pub room_event_links: BTreeMap<EventUuid, RoomUuid>,
+    rooms: BTreeMap<RoomUuid, UUIDRoomMapType>,
+    /// When each kind of room state was last changed. Older state events arriving late don't
+    /// overwrite newer ones.
+    room_state_ts: BTreeMap<(RoomUuid, &'static str), MilliSecondsSinceUnixEpoch>,
+    /// Rooms we only know from references like space children. Their properties are filled in
+    /// once we see the room itself.
+    room_stubs: BTreeSet<RoomUuid>,
+    /// How a room is listed in a space, keyed by space and child room.
+    space_children: BTreeMap<(RoomUuid, RoomUuid), SpaceChild>,
+    /// When the listing of a room in a space was last changed.
+    space_child_ts: BTreeMap<(RoomUuid, RoomUuid), MilliSecondsSinceUnixEpoch>,
+    memberships: BTreeMap<(UserUuid, RoomUuid), Membership>,
+    /// Every user seen so far with the last profile written for them.
+    users: BTreeMap<UserUuid, Option<UserProperties>>,
+    /// When the newest member event with the profile of a user was sent, so older ones found
+    /// while backfilling don't overwrite it.
+    profile_ts: BTreeMap<UserUuid, MilliSecondsSinceUnixEpoch>,
+    changes: Changes,
 }
 
 impl EventProperties {
     pub fn as_vec(&self, uuid: Uuid) -> Result<Vec<BulkInsertItem>> {
         match self {
             EventProperties::TextMessage(body, format, formatted_body) => {
-                let mut vector = Vec::with_capacity(3);
-                vector.push(utils::indradb::BulkInsertItem::VertexProperty(
-                    uuid,
-                    utils::indradb::Identifier::new("text_message_body")?,
-                    serde_json::Value::String(body.to_string()).into(),
-                ));
-                if let Some(format) = format {
-                    vector.push(utils::indradb::BulkInsertItem::VertexProperty(
+                // Missing values are written as null so an edit removing the formatting doesn't
+                // leave the old formatted body behind.
+                Ok(vec![
+                    utils::indradb::BulkInsertItem::VertexProperty(
+                        uuid,
+                        utils::indradb::Identifier::new("text_message_body")?,
+                        serde_json::Value::String(body.clone()).into(),
+                    ),
+                    utils::indradb::BulkInsertItem::VertexProperty(
                         uuid,
                         utils::indradb::Identifier::new("text_message_format")?,
-                        serde_json::Value::String(format.to_string()).into(),
-                    ));
-                }
-                if let Some(formatted_body) = formatted_body {
-                    vector.push(utils::indradb::BulkInsertItem::VertexProperty(
+                        format
+                            .clone()
+                            .map_or(serde_json::Value::Null, serde_json::Value::String)
+                            .into(),
+                    ),
+                    utils::indradb::BulkInsertItem::VertexProperty(
                         uuid,
                         utils::indradb::Identifier::new("text_message_formatted_body")?,
-                        serde_json::Value::String(formatted_body.to_string()).into(),
-                    ));
-                }
+                        formatted_body
+                            .clone()
+                            .map_or(serde_json::Value::Null, serde_json::Value::String)
+                            .into(),
+                    ),
+                ])
+            }
+            EventProperties::Media(media) => {
+                let properties: [(&str, serde_json::Value); 10] = [
+                    ("text_message_body", media.body.clone().into()),
+                    ("media_filename", media.filename.clone().into()),
+                    ("media_mimetype", media.mimetype.clone().into()),
+                    ("media_size", media.size.into()),
+                    ("media_width", media.width.into()),
+                    ("media_height", media.height.into()),
+                    ("media_duration", media.duration.into()),
+                    ("media_url", media.url.clone().into()),
+                    ("attachment_text", media.attachment_text.clone().into()),
+                    ("image_hash", media.image_hash.clone().into()),
+                ];
+                properties
+                    .into_iter()
+                    .map(|(name, value)| {
+                        Ok(utils::indradb::BulkInsertItem::VertexProperty(
+                            uuid,
+                            utils::indradb::Identifier::new(name)?,
+                            value.into(),
+                        ))
+                    })
+                    .collect()
+            }
+            EventProperties::Location(body, geo_uri) => Ok(vec![
+                utils::indradb::BulkInsertItem::VertexProperty(
+                    uuid,
+                    utils::indradb::Identifier::new("text_message_body")?,
+                    serde_json::Value::String(body.clone()).into(),
+                ),
+                utils::indradb::BulkInsertItem::VertexProperty(
+                    uuid,
+                    utils::indradb::Identifier::new("location_geo_uri")?,
+                    serde_json::Value::String(geo_uri.clone()).into(),
+                ),
+            ]),
+            EventProperties::Redacted => Ok(vec![utils::indradb::BulkInsertItem::VertexProperty(
+                uuid,
+                utils::indradb::Identifier::new("redacted")?,
+                serde_json::Value::Bool(true).into(),
+            )]),
+        }
+    }
+
+    /// The plain and, if there is one, HTML body which may reference links, users, rooms or
+    /// other events.
+    pub fn body_and_html(&self) -> Option<(&str, Option<&str>)> {
+        match self {
+            EventProperties::TextMessage(body, format, formatted_body) => {
+                let html = format
+                    .as_deref()
+                    .filter(|format| *format == "org.matrix.custom.html")
+                    .and(formatted_body.as_deref());
+                Some((body, html))
+            }
+            EventProperties::Media(media) => Some((&media.body, None)),
+            EventProperties::Location(..) | EventProperties::Redacted => None,
+        }
+    }
+
+    /// The normalized URLs mentioned in the event.
+    pub fn links(&self) -> BTreeSet<String> {
+        self.body_and_html()
+            .map(|(body, html)| links::extract(body, html))
+            .unwrap_or_default()
+    }
 
-                Ok(vector)
+    /// The text of the event which gets fed into the full-text index.
+    pub fn searchable_text(&self) -> Vec<&str> {
+        match self {
+            EventProperties::TextMessage(body, _, _) | EventProperties::Location(body, _) => {
+                vec![body.as_str()]
+            }
+            EventProperties::Media(media) => {
+                let mut text = vec![media.body.as_str()];
+                // The body is only the filename if there is no caption
+                text.extend(
+                    media
+                        .filename
+                        .as_deref()
+                        .filter(|filename| *filename != media.body),
+                );
+                text.extend(media.attachment_text.as_deref());
+                text
             }
+            EventProperties::Redacted => Vec::new(),
         }
     }
+
+    /// Reads the content back from the properties written by [`EventProperties::as_vec`].
+    ///
+    /// Returns `None` if the properties hold no content.
+    pub fn from_properties(properties: &BTreeMap<String, serde_json::Value>) -> Option<Self> {
+        let string = |name: &str| {
+            properties
+                .get(name)
+                .and_then(serde_json::Value::as_str)
+                .map(ToString::to_string)
+        };
+        let number = |name: &str| properties.get(name).and_then(serde_json::Value::as_u64);
+
+        if properties.get("redacted") == Some(&serde_json::Value::Bool(true)) {
+            return Some(EventProperties::Redacted);
+        }
+        let body = string("text_message_body")?;
+        if let Some(geo_uri) = string("location_geo_uri") {
+            return Some(EventProperties::Location(body, geo_uri));
+        }
+        // Media content always writes its url, if only as null
+        if properties.contains_key("media_url") {
+            return Some(EventProperties::Media(MediaProperties {
+                body,
+                filename: string("media_filename"),
+                mimetype: string("media_mimetype"),
+                size: number("media_size"),
+                width: number("media_width"),
+                height: number("media_height"),
+                duration: number("media_duration"),
+                url: string("media_url"),
+                attachment_text: string("attachment_text"),
+                image_hash: string("image_hash"),
+            }));
+        }
+        Some(EventProperties::TextMessage(
+            body,
+            string("text_message_format"),
+            string("text_message_formatted_body"),
+        ))
+    }
+}
+
+/// A version of the content of an edited event as stored on its revision vertex.
+pub struct StoredRevision {
+    /// The original event or the edit which carried the content.
+    pub event_id: String,
+    pub origin_server_ts: u64,
+    pub event_properties: EventProperties,
+}
+
+impl StoredRevision {
+    /// Reads a revision from the properties of its vertex.
+    pub fn from_properties(properties: &BTreeMap<String, serde_json::Value>) -> Option<Self> {
+        Some(StoredRevision {
+            event_id: properties.get("revision_event_id")?.as_str()?.to_string(),
+            origin_server_ts: properties.get("origin_server_ts")?.as_u64()?,
+            event_properties: EventProperties::from_properties(properties)?,
+        })
+    }
+
+    /// The revision an event falls back to once the edit it shows was redacted.
+    ///
+    /// Only revisions by the sender of the event are stored, so the newest remaining one is the
+    /// content the event had before. If no edit is left that is the original revision.
+    pub fn newest(revisions: impl IntoIterator<Item = StoredRevision>) -> Option<StoredRevision> {
+        revisions
+            .into_iter()
+            .max_by_key(|revision| revision.origin_server_ts)
+    }
+}
+
+impl RoomProperties {
+    /// The text of the room which gets fed into the full-text index.
+    pub fn searchable_text(&self) -> Vec<&str> {
+        self.name
+            .iter()
+            .chain(self.topic.iter())
+            .chain(self.canonical_alias.iter())
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+impl UUIDUserMapType {
+    /// The text of the user which gets fed into the full-text index.
+    pub fn searchable_text(&self) -> Vec<&str> {
+        let mut text = vec![self.user_id.as_str()];
+        text.extend(
+            self.user_properties
+                .as_ref()
+                .and_then(|properties| properties.display_name.as_deref()),
+        );
+        text
+    }
 }
 
 impl MessagesMap {
+    /// Hands out everything changed since the last call and starts over with an empty buffer.
+    pub fn take_changes(&mut self) -> Changes {
+        take(&mut self.changes)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn insert_event(
         &mut self,
         event_id: OwnedEventId,
         room_uuid: RoomUuid,
         event_type: utils::indradb::Identifier,
         event_properties: EventProperties,
+        mentions: MentionTargets,
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+        sender: OwnedUserId,
     ) -> EventUuid {
-        // FIXME: We need to actually look them up here to get the uuid since we index live.
-        // This means the uuid list might not have all events when we are indexing reactions.
-        if let Some(&uuid) = self.event_uuids.get(&event_id) {
+        let uuid = ids::event_uuid(event_id.as_str());
+        if self.changes.message_list.contains_key(&uuid) {
             return uuid;
         }
+        // A placeholder gets filled in by writing the real vertex with the same uuid
+        self.changes.placeholders.remove(&uuid);
 
-        let uuid = Uuid::new_v4();
+        let (event_properties, mentions) = if self.changes.redactions.contains(&event_id) {
+            (EventProperties::Redacted, MentionTargets::default())
+        } else {
+            (event_properties, mentions)
+        };
+        let sender_uuid = ids::user_uuid(sender.as_str());
+        if self.users.contains_key(&sender_uuid) {
+            self.changes.sender_links.insert(uuid, sender_uuid);
+        }
         let map_thingy = UUIDEventMapType {
-            event_id: event_id.clone(),
+            event_id,
             uuid,
             event_type,
             event_properties,
+            mentions,
+            origin_server_ts,
+            sender,
         };
-        self.event_uuids.insert(event_id, uuid);
-        self.message_list.push_back(map_thingy);
-        self.room_event_links.insert(uuid, room_uuid);
+        self.changes.message_list.insert(uuid, map_thingy);
+        self.changes.room_event_links.insert(uuid, room_uuid);
+        uuid
+    }
+
+    /// Returns the uuid of an event and creates a placeholder unless the event is part of the
+    /// current changes.
+    pub fn event_uuid_or_placeholder(&mut self, event_id: OwnedEventId) -> EventUuid {
+        let uuid = ids::event_uuid(event_id.as_str());
+        if !self.changes.message_list.contains_key(&uuid) {
+            self.changes.placeholders.insert(uuid, event_id);
+        }
         uuid
     }
 
+    pub fn insert_reply(&mut self, event_uuid: EventUuid, in_reply_to: OwnedEventId) {
+        let reply_uuid = self.event_uuid_or_placeholder(in_reply_to);
+        self.changes.reply_links.insert(event_uuid, reply_uuid);
+    }
+
+    pub fn insert_thread(&mut self, event_uuid: EventUuid, thread_root: OwnedEventId) {
+        let thread_uuid = self.event_uuid_or_placeholder(thread_root);
+        self.changes.thread_links.insert(event_uuid, thread_uuid);
+    }
+
+    /// Queues an `m.replace` edit of the original event.
+    ///
+    /// Every version of the content is kept as a revision while the original event always holds
+    /// the newest one, so search only matches the current text. Which edit is the newest and
+    /// whether its sender may edit the event at all is decided while pushing.
+    pub fn insert_edit(&mut self, edit: UUIDEditMapType) {
+        if self.changes.redactions.contains(&edit.original_event_id)
+            || self.changes.redactions.contains(&edit.event_id)
+        {
+            return;
+        }
+        let original_uuid = self.event_uuid_or_placeholder(edit.original_event_id.clone());
+        self.changes.revision_list.push(UUIDRevisionMapType {
+            uuid: ids::revision_uuid(edit.event_id.as_str()),
+            event_id: edit.event_id.clone(),
+            revision_of: original_uuid,
+            event_properties: edit.event_properties.clone(),
+            origin_server_ts: edit.origin_server_ts,
+            sender: edit.sender.clone(),
+        });
+        self.changes
+            .edits
+            .entry(original_uuid)
+            .or_default()
+            .push(edit);
+    }
+
+    /// Removes the content of a redacted event, which may also be an edit or a reaction, from
+    /// the changes and queues it for removal from indradb.
+    pub fn insert_redaction(&mut self, event_id: OwnedEventId) {
+        if self.changes.redactions.contains(&event_id) {
+            return;
+        }
+
+        let uuid = ids::event_uuid(event_id.as_str());
+        if let Some(event) = self.changes.message_list.get_mut(&uuid) {
+            event.event_properties = EventProperties::Redacted;
+            event.mentions = MentionTargets::default();
+        }
+        self.changes.edits.remove(&uuid);
+        for edits in self.changes.edits.values_mut() {
+            edits.retain(|edit| edit.event_id != event_id);
+        }
+        self.changes
+            .revision_list
+            .retain(|revision| revision.revision_of != uuid && revision.event_id != event_id);
+        self.changes.reactions.remove(&event_id);
+        self.changes.redactions.insert(event_id);
+    }
+
+    pub fn insert_reaction(
+        &mut self,
+        reaction_event_id: OwnedEventId,
+        user_uuid: UserUuid,
+        reacted_to: OwnedEventId,
+        key: String,
+    ) {
+        if self.changes.redactions.contains(&reaction_event_id) {
+            return;
+        }
+
+        let event_uuid = self.event_uuid_or_placeholder(reacted_to);
+        self.changes
+            .reactions
+            .insert(reaction_event_id, (user_uuid, event_uuid, key));
+    }
+
+    /// Inserts a room we see the state of. `names` are the names the room had according to
+    /// what was stored before, they are only used if the room was not loaded yet.
     pub fn insert_room(
         &mut self,
         room_id: OwnedRoomId,
         room_properties: RoomProperties,
+        names: BTreeSet<String>,
     ) -> RoomUuid {
-        // FIXME: We need to actually look them up here to get the uuid since we index live.
-        // This means the uuid list might not have all events when we are indexing reactions.
-        // FIXME: we probably need to also make updates for roomnames and topic here
-        if let Some(&uuid) = self.room_uuids.get(&room_id) {
+        let uuid = ids::room_uuid(room_id.as_str());
+        // Later changes of the room are applied using `update_room`.
+        if let Some(room) = self.rooms.get_mut(&uuid) {
+            if self.room_stubs.remove(&uuid) {
+                room.room_properties = room_properties;
+                room.names.extend(names);
+                self.changes.rooms.insert(uuid, room.clone());
+            }
             return uuid;
         }
 
-        let uuid = Uuid::new_v4();
         let map_thingy = UUIDRoomMapType {
-            room_id: room_id.clone(),
+            room_id,
             uuid,
             room_properties,
+            names,
         };
-        self.room_uuids.insert(room_id, uuid);
-        self.room_list.push_back(map_thingy);
+        self.changes.rooms.insert(uuid, map_thingy.clone());
+        self.rooms.insert(uuid, map_thingy);
         uuid
     }
+
+    /// Whether the room was inserted with its state since the start.
+    pub fn is_loaded(&self, room_uuid: RoomUuid) -> bool {
+        self.rooms.contains_key(&room_uuid) && !self.room_stubs.contains(&room_uuid)
+    }
+
+    /// Whether we only know the room from references and never saw its state.
+    pub fn is_stub(&self, room_uuid: RoomUuid) -> bool {
+        self.room_stubs.contains(&room_uuid)
+    }
+
+    /// Returns the uuid of a room or inserts it without any properties if we did not see it yet.
+    pub fn room_uuid_or_stub(&mut self, room_id: OwnedRoomId) -> RoomUuid {
+        let uuid = ids::room_uuid(room_id.as_str());
+        if self.rooms.contains_key(&uuid) {
+            return uuid;
+        }
+
+        self.insert_room(room_id, RoomProperties::default(), BTreeSet::new());
+        self.room_stubs.insert(uuid);
+        uuid
+    }
+
+    /// Adds, updates or with `None` removes a room from a space.
+    pub fn insert_space_child(
+        &mut self,
+        space_uuid: RoomUuid,
+        child_uuid: RoomUuid,
+        child: Option<SpaceChild>,
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+    ) {
+        let key = (space_uuid, child_uuid);
+        if self
+            .space_child_ts
+            .get(&key)
+            .is_some_and(|latest| *latest > origin_server_ts)
+        {
+            return;
+        }
+        self.space_child_ts.insert(key, origin_server_ts);
+
+        if let Some(child) = child {
+            self.changes
+                .removed_space_children
+                .retain(|&removed| removed != key);
+            if self.space_children.get(&key) != Some(&child) {
+                self.space_children.insert(key, child.clone());
+                self.changes.space_children.insert(key, child);
+            }
+        } else if self.space_children.remove(&key).is_some() {
+            self.changes.space_children.remove(&key);
+            self.changes.removed_space_children.push(key);
+        }
+    }
+
+    /// Adds a room to a space as claimed by the room itself.
+    ///
+    /// The listing in the space is authoritative, so this only adds the edge if the space did
+    /// not list the room yet.
+    pub fn insert_space_parent(&mut self, space_uuid: RoomUuid, child_uuid: RoomUuid) {
+        let key = (space_uuid, child_uuid);
+        if self.space_child_ts.contains_key(&key) || self.space_children.contains_key(&key) {
+            return;
+        }
+        self.space_children.insert(key, SpaceChild::default());
+        self.changes
+            .space_children
+            .insert(key, SpaceChild::default());
+    }
+
+    pub fn insert_membership(
+        &mut self,
+        user_uuid: UserUuid,
+        room_uuid: RoomUuid,
+        state: String,
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+    ) {
+        let key = (user_uuid, room_uuid);
+        let joined = state == "join";
+        let membership = match self.memberships.get_mut(&key) {
+            // Member events can arrive out of order, an older one changes nothing
+            Some(membership) if membership.updated_at > origin_server_ts => return,
+            Some(membership) => {
+                membership.updated_at = origin_server_ts;
+                // Profile changes are joins as well but don't change when the user joined
+                if membership.state == state {
+                    return;
+                }
+                if joined {
+                    membership.joined_at = Some(origin_server_ts);
+                }
+                membership.state = state;
+                membership.clone()
+            }
+            None => {
+                let membership = Membership {
+                    state,
+                    joined_at: joined.then_some(origin_server_ts),
+                    updated_at: origin_server_ts,
+                };
+                self.memberships.insert(key, membership.clone());
+                membership
+            }
+        };
+        self.changes.memberships.insert(key, membership);
+    }
+
+    /// Applies a state event to a room inserted before.
+    pub fn update_room(
+        &mut self,
+        room_uuid: RoomUuid,
+        update: RoomStateUpdate,
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+    ) {
+        let Some(room) = self.rooms.get_mut(&room_uuid) else {
+            return;
+        };
+        let mut changed = false;
+        if let RoomStateUpdate::Name(Some(name)) = &update {
+            // The same state event can show up in the state and the timeline of a sync
+            let known = self.changes.room_name_list.iter().any(|room_name| {
+                room_name.room_uuid == room_uuid
+                    && room_name.origin_server_ts == origin_server_ts
+                    && room_name.name == *name
+            });
+            if !known {
+                self.changes.room_name_list.push(UUIDRoomNameMapType {
+                    uuid: ids::room_name_change_uuid(room_uuid, u64::from(origin_server_ts.0)),
+                    room_uuid,
+                    name: name.clone(),
+                    origin_server_ts,
+                });
+            }
+            changed |= room.names.insert(name.clone());
+        }
+
+        let key = (room_uuid, update.kind());
+        let outdated = self
+            .room_state_ts
+            .get(&key)
+            .is_some_and(|latest| *latest > origin_server_ts);
+        if !outdated {
+            self.room_state_ts.insert(key, origin_server_ts);
+            let properties = &mut room.room_properties;
+            let (property, value) = match update {
+                RoomStateUpdate::Name(name) => (&mut properties.name, name),
+                RoomStateUpdate::Topic(topic) => (&mut properties.topic, topic),
+                RoomStateUpdate::CanonicalAlias(alias) => (&mut properties.canonical_alias, alias),
+                RoomStateUpdate::Avatar(avatar_url) => (&mut properties.avatar_url, avatar_url),
+            };
+            if *property != value {
+                *property = value;
+                changed = true;
+            }
+        }
+        if changed {
+            self.changes.rooms.insert(room_uuid, room.clone());
+        }
+    }
+
+    /// Makes sure the user has a vertex. The profile is only taken from member events, see
+    /// [`MessagesMap::update_user_profile`].
+    pub fn insert_user(&mut self, user_id: OwnedUserId) -> UserUuid {
+        let uuid = ids::user_uuid(user_id.as_str());
+        if self.users.contains_key(&uuid) {
+            return uuid;
+        }
+        self.users.insert(uuid, None);
+
+        let map_thingy = UUIDUserMapType {
+            user_id,
+            uuid,
+            user_properties: None,
+        };
+        self.changes.user_list.insert(uuid, map_thingy);
+        uuid
+    }
+
+    /// Records the profile of a user from a member event, unless a newer one was seen already.
+    /// It is only written again once it changed.
+    pub fn update_user_profile(
+        &mut self,
+        user_id: OwnedUserId,
+        user_properties: UserProperties,
+        origin_server_ts: MilliSecondsSinceUnixEpoch,
+    ) -> UserUuid {
+        let uuid = ids::user_uuid(user_id.as_str());
+        if self
+            .profile_ts
+            .get(&uuid)
+            .is_some_and(|updated_at| *updated_at > origin_server_ts)
+        {
+            return uuid;
+        }
+        self.profile_ts.insert(uuid, origin_server_ts);
+        let user_properties = Some(user_properties);
+        if self.users.get(&uuid) == Some(&user_properties) {
+            return uuid;
+        }
+        self.users.insert(uuid, user_properties.clone());
+
+        let map_thingy = UUIDUserMapType {
+            user_id,
+            uuid,
+            user_properties,
+        };
+        self.changes.user_list.insert(uuid, map_thingy);
+        uuid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The properties a revision vertex is stored with.
+    fn revision(
+        event_id: &str,
+        origin_server_ts: u64,
+        event_properties: &EventProperties,
+    ) -> BTreeMap<String, serde_json::Value> {
+        let mut properties: BTreeMap<String, serde_json::Value> = event_properties
+            .as_vec(Uuid::nil())
+            .expect("Valid identifiers")
+            .into_iter()
+            .filter_map(|item| match item {
+                BulkInsertItem::VertexProperty(_, name, value) => {
+                    Some((name.to_string(), (*value).clone()))
+                }
+                _ => None,
+            })
+            .collect();
+        properties.insert("revision_event_id".to_string(), event_id.into());
+        properties.insert("origin_server_ts".to_string(), origin_server_ts.into());
+        properties
+    }
+
+    fn text(body: &str) -> EventProperties {
+        EventProperties::TextMessage(body.to_string(), None, None)
+    }
+
+    #[test]
+    fn content_round_trip() {
+        let media = EventProperties::Media(MediaProperties {
+            body: "cat.png".to_string(),
+            filename: Some("cat.png".to_string()),
+            mimetype: Some("image/png".to_string()),
+            width: Some(640),
+            url: Some("mxc://example.org/cat".to_string()),
+            ..MediaProperties::default()
+        });
+        let html = EventProperties::TextMessage(
+            "**hi**".to_string(),
+            Some("org.matrix.custom.html".to_string()),
+            Some("<b>hi</b>".to_string()),
+        );
+        let location = EventProperties::Location("Home".to_string(), "geo:1,2".to_string());
+        for event_properties in [media, html, location, EventProperties::Redacted] {
+            let properties = revision("$event", 1, &event_properties);
+            let read = EventProperties::from_properties(&properties).expect("Content is stored");
+            assert_eq!(
+                read.as_vec(Uuid::nil()).expect("Valid identifiers"),
+                event_properties
+                    .as_vec(Uuid::nil())
+                    .expect("Valid identifiers")
+            );
+        }
+    }
+
+    #[test]
+    fn redacting_the_latest_edit_restores_the_previous_content() {
+        let original = revision("$original", 1, &text("hello"));
+        let first_edit = revision("$first", 2, &text("hello world"));
+        // The latest edit with the content "hello there" was redacted and its revision deleted
+        let remaining = [&original, &first_edit];
+
+        let restored = StoredRevision::newest(
+            remaining
+                .iter()
+                .filter_map(|properties| StoredRevision::from_properties(properties)),
+        )
+        .expect("A revision is left");
+        assert_eq!(restored.event_id, "$first");
+        assert_eq!(restored.origin_server_ts, 2);
+        assert_eq!(restored.event_properties.searchable_text(), ["hello world"]);
+
+        // Without any edit left the event shows what it was sent with
+        let restored = StoredRevision::newest(StoredRevision::from_properties(&original))
+            .expect("The original is left");
+        assert_eq!(restored.event_id, "$original");
+        assert_eq!(restored.event_properties.searchable_text(), ["hello"]);
+    }
 }
@@ -0,0 +1,197 @@
+use std::{borrow::Cow, collections::BTreeSet};
+
+use url::Url;
+
+/// Characters which commonly follow a URL in prose but are rarely part of it.
+const TRAILING_PUNCTUATION: &[char] = &['.', ',', ';', ':', '!', '?', '\'', '"', '>', '*', '_'];
+
+/// Normalizes a URL so the same page shared in different ways maps to the same `link` vertex.
+///
+/// Scheme and host are lowercased and default ports dropped by the parser. Fragments and a
+/// trailing slash are removed as they rarely point at a different resource. Fragments starting
+/// with `/` are kept since single page apps like `matrix.to` route with them.
+pub fn normalize(url: &str) -> Option<String> {
+    let mut url = Url::parse(url).ok()?;
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        return None;
+    }
+    if !url
+        .fragment()
+        .is_some_and(|fragment| fragment.starts_with('/'))
+    {
+        url.set_fragment(None);
+    }
+    let mut normalized = url.to_string();
+    if url.query().is_none() && url.fragment().is_none() && normalized.ends_with('/') {
+        normalized.pop();
+    }
+    Some(normalized)
+}
+
+/// Removes punctuation the URL was embedded in, keeping closing parentheses which belong to the
+/// URL like in Wikipedia links.
+fn trim_url(mut url: &str) -> &str {
+    loop {
+        let trimmed = url.trim_end_matches(TRAILING_PUNCTUATION);
+        let trimmed = match trimmed.strip_suffix(')') {
+            Some(stripped) if trimmed.matches('(').count() < trimmed.matches(')').count() => {
+                stripped
+            }
+            _ => trimmed,
+        };
+        if trimmed.len() == url.len() {
+            return url;
+        }
+        url = trimmed;
+    }
+}
+
+/// Finds `http(s)://` URLs in plain text.
+fn plain_urls(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| c.is_whitespace() || matches!(c, '<' | '"' | '[' | ']'))
+        .filter_map(|word| {
+            let start = word.find("https://").or_else(|| word.find("http://"))?;
+            Some(trim_url(&word[start..]))
+        })
+}
+
+/// Finds the targets of `<a href>` tags in HTML.
+fn href_urls(html: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find("href=") {
+        rest = &rest[start + "href=".len()..];
+        let Some(quote) = rest.chars().next().filter(|c| matches!(c, '"' | '\'')) else {
+            continue;
+        };
+        rest = &rest[1..];
+        let Some(end) = rest.find(quote) else {
+            break;
+        };
+        urls.push(rest[..end].replace("&amp;", "&"));
+        rest = &rest[end..];
+    }
+    urls
+}
+
+/// Removes the quote of the replied to message which clients put in front of replies, so it
+/// doesn't count as content of the reply.
+pub fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    // The fallback is separated from the reply by an empty line
+    body.split_once("\n\n").map_or(body, |(_, reply)| reply)
+}
+
+/// The HTML version of [`strip_reply_fallback`].
+fn strip_html_reply_fallback(html: &str) -> Cow<'_, str> {
+    const END: &str = "</mx-reply>";
+    match (html.find("<mx-reply>"), html.find(END)) {
+        (Some(start), Some(end)) if start < end => {
+            Cow::Owned(format!("{}{}", &html[..start], &html[end + END.len()..]))
+        }
+        _ => Cow::Borrowed(html),
+    }
+}
+
+/// Finds the URLs in the plain and HTML body of a message, leaving out reply fallbacks.
+pub fn urls(body: &str, formatted_body: Option<&str>) -> Vec<String> {
+    let mut urls: Vec<String> = plain_urls(strip_reply_fallback(body))
+        .map(ToString::to_string)
+        .collect();
+    if let Some(formatted_body) = formatted_body {
+        urls.extend(href_urls(&strip_html_reply_fallback(formatted_body)));
+    }
+    urls
+}
+
+/// Collects the normalized URLs of a message from its plain and HTML body.
+pub fn extract(body: &str, formatted_body: Option<&str>) -> BTreeSet<String> {
+    urls(body, formatted_body)
+        .iter()
+        .filter_map(|url| normalize(url))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_urls() {
+        assert_eq!(
+            normalize("HTTPS://Example.ORG:443/Path/").as_deref(),
+            Some("https://example.org/Path")
+        );
+        assert_eq!(
+            normalize("http://example.org/page#section").as_deref(),
+            Some("http://example.org/page")
+        );
+        assert_eq!(
+            normalize("https://example.org/?q=1").as_deref(),
+            Some("https://example.org/?q=1")
+        );
+        assert_eq!(
+            normalize("https://matrix.to/#/#room:example.org").as_deref(),
+            Some("https://matrix.to/#/#room:example.org")
+        );
+    }
+
+    #[test]
+    fn normalize_rejects_other_schemes() {
+        assert_eq!(normalize("mailto:user@example.org"), None);
+        assert_eq!(normalize("ftp://example.org/file"), None);
+        assert_eq!(normalize("matrix:r/room:example.org"), None);
+        assert_eq!(normalize("not a url"), None);
+    }
+
+    #[test]
+    fn extract_from_plain_text() {
+        let links = extract(
+            "See https://example.org/a. Or (https://en.wikipedia.org/wiki/Rust_(language)), \
+             and \"http://example.org/b\"!",
+            None,
+        );
+        assert_eq!(
+            links,
+            BTreeSet::from([
+                "http://example.org/b".to_string(),
+                "https://en.wikipedia.org/wiki/Rust_(language)".to_string(),
+                "https://example.org/a".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn extract_from_html() {
+        let links = extract(
+            "link",
+            Some(
+                r#"<a href="https://example.org/?a=1&amp;b=2">link</a> <a href='https://example.org/c/'>c</a>"#,
+            ),
+        );
+        assert_eq!(
+            links,
+            BTreeSet::from([
+                "https://example.org/?a=1&b=2".to_string(),
+                "https://example.org/c".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn extract_skips_reply_fallbacks() {
+        let links = extract(
+            "> <@user:example.org> https://example.org/quoted\n\nhttps://example.org/reply",
+            Some(
+                "<mx-reply><a href=\"https://example.org/quoted\">quoted</a></mx-reply>\
+                 <a href=\"https://example.org/reply\">reply</a>",
+            ),
+        );
+        assert_eq!(
+            links,
+            BTreeSet::from(["https://example.org/reply".to_string()])
+        );
+    }
+}
@@ -15,7 +15,6 @@
     clippy::suboptimal_flops,
     clippy::fn_to_numeric_cast_any,
     clippy::if_then_some_else_none,
-    clippy::imprecise_flops,
     clippy::lossy_float_literal,
     clippy::panic_in_result_fn,
     clippy::clone_on_ref_ptr
@@ -32,9 +31,14 @@ use config::{load, write_access_token};
 use matrix::IndexerBot;
 use tracing::info;
 
+mod attachments;
+mod backfill;
 mod config;
 mod indradb_utils;
+mod links;
 mod matrix;
+mod mentions;
+mod sync_token;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -52,7 +56,13 @@ async fn main() -> Result<()> {
         String::from("room_id"),
         String::from("room_name"),
         String::from("room_topic"),
+        String::from("room_canonical_alias"),
         String::from("event_id"),
+        String::from("sender"),
+        String::from("user_id"),
+        String::from("revision_event_id"),
+        String::from("image_hash"),
+        String::from("link_url"),
     ]);
     utils::add_identifiers(&mut identifiers)?;
 
@@ -64,6 +74,8 @@ async fn main() -> Result<()> {
                 mxid,
                 password,
                 config.indradb_endpoint,
+                config.max_attachment_size,
+                config.backfill,
             )
             .await?;
             let access_token = bot.access_token();
@@ -81,6 +93,8 @@ async fn main() -> Result<()> {
                 access_token,
                 device_id,
                 config.indradb_endpoint,
+                config.max_attachment_size,
+                config.backfill,
             )
             .await?
         }
@@ -1,30 +1,169 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::Duration,
+};
 
-use crate::indradb_utils::{BulkInserter, MessagesMap, UUIDEventMapType, UUIDRoomMapType};
-use color_eyre::Result;
+use crate::{
+    attachments::{self, AttachmentKind},
+    backfill::{self, BackfillLimits, Checkpoints},
+    indradb_utils::{
+        BulkInserter, Changes, EventProperties, EventUuid, MediaProperties, MentionTargets,
+        MessagesMap, RoomProperties, RoomStateUpdate, RoomUuid, SpaceChild, StoredRevision,
+        UUIDEditMapType, UUIDEventMapType, UUIDRevisionMapType, UUIDRoomMapType, UserProperties,
+        UserUuid,
+    },
+    mentions::{AliasCache, Mentions},
+    sync_token::{self, SyncToken},
+};
+use color_eyre::{eyre::bail, Result};
 use futures::StreamExt;
 use matrix_sdk::{
     config::SyncSettings,
+    room::{Joined, Messages, MessagesOptions},
     ruma::{
         events::{
-            room::message::MessageType, AnySyncMessageLikeEvent, AnySyncTimelineEvent,
-            SyncMessageLikeEvent,
+            reaction::OriginalSyncReactionEvent,
+            room::{
+                message::{
+                    FileMessageEventContent, ImageMessageEventContent, MessageType,
+                    OriginalSyncRoomMessageEvent, Relation,
+                },
+                power_levels::{PowerLevelAction, RoomPowerLevels},
+                redaction::SyncRoomRedactionEvent,
+                MediaSource,
+            },
+            space::parent::OriginalSyncSpaceParentEvent,
+            AnySyncMessageLikeEvent, AnySyncStateEvent, AnySyncTimelineEvent, StateEventType,
+            SyncMessageLikeEvent, SyncStateEvent,
         },
-        OwnedUserId,
+        serde::Raw,
+        MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedUserId,
+        RoomAliasId, RoomId,
     },
     Client, Session,
 };
-use tracing::{error, info};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+use utils::{
+    ids,
+    indradb::{
+        util::{
+            extract_edge_properties, extract_edges, extract_vertex_properties, extract_vertices,
+        },
+        Json, QueryExt, SpecificEdgeQuery, SpecificVertexQuery, VertexProperties,
+    },
+    text_index::TextIndex,
+};
+use uuid::Uuid;
+
+/// The `mxc://` URI of a media message.
+fn media_url(source: &MediaSource) -> String {
+    match source {
+        MediaSource::Plain(url) => url.to_string(),
+        MediaSource::Encrypted(file) => file.url.to_string(),
+    }
+}
+
+fn duration_ms(duration: Duration) -> Option<u64> {
+    u64::try_from(duration.as_millis()).ok()
+}
 
+/// Properties of an event vertex which survive a redaction.
+const KEPT_ON_REDACTION: [&str; 3] = ["event_id", "origin_server_ts", "sender"];
+/// Properties of an event vertex which are not part of its content.
+const EVENT_METADATA: [&str; 7] = [
+    "event_id",
+    "origin_server_ts",
+    "sender",
+    "redacted",
+    "edited_at",
+    "reaction_count",
+    "reaction_keys",
+];
+
+/// The properties of a vertex by name.
+fn property_map(vertex_properties: &VertexProperties) -> BTreeMap<String, serde_json::Value> {
+    vertex_properties
+        .props
+        .iter()
+        .map(|property| (property.name.to_string(), (*property.value).clone()))
+        .collect()
+}
+
+/// A `/messages` request for the next page of a room being backfilled.
+type PageRequest = Pin<Box<dyn Future<Output = (Joined, matrix_sdk::Result<Messages>)> + Send>>;
+
+/// Reactions on `reacted_with` edges, keyed by the event id of each reaction.
+type Reactions = BTreeMap<(UserUuid, EventUuid), BTreeMap<String, String>>;
+
+/// What changed while merging reactions with the ones stored in indradb.
+#[derive(Default)]
+struct ReactionUpdate {
+    /// The reaction keys of every event whose reactions changed.
+    keys: BTreeMap<EventUuid, Vec<String>>,
+    /// `reacted_with` edges which lost their last reaction.
+    removed: Vec<(UserUuid, EventUuid)>,
+}
+
+#[allow(clippy::struct_field_names)]
 struct Identifiers {
     room_type: utils::indradb::Identifier,
     room_id_type: utils::indradb::Identifier,
     room_name_type: utils::indradb::Identifier,
     room_topic_type: utils::indradb::Identifier,
+    room_canonical_alias_type: utils::indradb::Identifier,
+    room_avatar_url_type: utils::indradb::Identifier,
+    room_name_change_type: utils::indradb::Identifier,
+    name_change_of_type: utils::indradb::Identifier,
+    space_contains_type: utils::indradb::Identifier,
+    order_type: utils::indradb::Identifier,
+    suggested_type: utils::indradb::Identifier,
+    member_of_type: utils::indradb::Identifier,
+    membership_type: utils::indradb::Identifier,
+    joined_at_type: utils::indradb::Identifier,
     text_message_event_type: utils::indradb::Identifier,
     notice_message_event_type: utils::indradb::Identifier,
+    emote_message_event_type: utils::indradb::Identifier,
+    image_message_event_type: utils::indradb::Identifier,
+    file_message_event_type: utils::indradb::Identifier,
+    video_message_event_type: utils::indradb::Identifier,
+    audio_message_event_type: utils::indradb::Identifier,
+    location_message_event_type: utils::indradb::Identifier,
     event_id_type: utils::indradb::Identifier,
+    origin_server_ts_type: utils::indradb::Identifier,
+    sender_type: utils::indradb::Identifier,
     event_in_room_type: utils::indradb::Identifier,
+    user_type: utils::indradb::Identifier,
+    user_id_type: utils::indradb::Identifier,
+    user_display_name_type: utils::indradb::Identifier,
+    user_avatar_url_type: utils::indradb::Identifier,
+    sent_by_type: utils::indradb::Identifier,
+    placeholder_event_type: utils::indradb::Identifier,
+    reply_to_type: utils::indradb::Identifier,
+    in_thread_type: utils::indradb::Identifier,
+    revision_type: utils::indradb::Identifier,
+    revision_event_id_type: utils::indradb::Identifier,
+    revision_of_type: utils::indradb::Identifier,
+    edited_at_type: utils::indradb::Identifier,
+    redacted_type: utils::indradb::Identifier,
+    reacted_with_type: utils::indradb::Identifier,
+    reaction_keys_type: utils::indradb::Identifier,
+    reaction_count_type: utils::indradb::Identifier,
+    reaction_events_type: utils::indradb::Identifier,
+    reaction_vertex_type: utils::indradb::Identifier,
+    reacted_by_type: utils::indradb::Identifier,
+    reacted_to_type: utils::indradb::Identifier,
+    link_type: utils::indradb::Identifier,
+    link_url_type: utils::indradb::Identifier,
+    mentions_link_type: utils::indradb::Identifier,
+    mentions_user_type: utils::indradb::Identifier,
+    mentions_room_type: utils::indradb::Identifier,
+    links_to_event_type: utils::indradb::Identifier,
 }
 
 pub struct IndexerBot {
@@ -32,6 +171,20 @@ pub struct IndexerBot {
     indexer_client: utils::indradb_proto::Client,
     message_map: MessagesMap,
     identifiers: Identifiers,
+    text_index: TextIndex,
+    max_attachment_size: u64,
+    /// Downloads attachments, see [`attachments::download`].
+    http_client: reqwest::Client,
+    /// Room aliases mentioned in messages and the rooms they resolved to.
+    room_aliases: AliasCache,
+    /// How far joined rooms get backfilled. `None` disables backfilling.
+    backfill: Option<BackfillLimits>,
+    /// Rooms left to backfill, starting with the one in progress. Filled after the first sync.
+    backfill_queue: Option<VecDeque<OwnedRoomId>>,
+    checkpoints: Checkpoints,
+    /// Where the timeline of each room in the first sync started. Backfills start from there.
+    prev_batches: BTreeMap<OwnedRoomId, String>,
+    sync_token: SyncToken,
 }
 
 impl IndexerBot {
@@ -49,6 +202,7 @@ impl IndexerBot {
         Ok(client_builder.build().await?)
     }
 
+    #[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
     async fn get_indexer_client(
         endpoint: String,
     ) -> Result<(utils::indradb_proto::Client, Identifiers)> {
@@ -59,14 +213,68 @@ impl IndexerBot {
         let room_id_type = utils::indradb::Identifier::new("room_id")?;
         let room_name_type = utils::indradb::Identifier::new("room_name")?;
         let room_topic_type = utils::indradb::Identifier::new("room_topic")?;
+        let room_canonical_alias_type = utils::indradb::Identifier::new("room_canonical_alias")?;
+        let room_avatar_url_type = utils::indradb::Identifier::new("room_avatar_url")?;
+        let room_name_change_type = utils::indradb::Identifier::new("room_name_change")?;
+        let name_change_of_type = utils::indradb::Identifier::new("name_change_of")?;
+        let space_contains_type = utils::indradb::Identifier::new("space_contains")?;
+        let order_type = utils::indradb::Identifier::new("order")?;
+        let suggested_type = utils::indradb::Identifier::new("suggested")?;
+        let member_of_type = utils::indradb::Identifier::new("member_of")?;
+        let membership_type = utils::indradb::Identifier::new("membership")?;
+        let joined_at_type = utils::indradb::Identifier::new("joined_at")?;
         let text_message_event_type = utils::indradb::Identifier::new("text_message_event")?;
         let notice_message_event_type = utils::indradb::Identifier::new("notice_message_event")?;
+        let emote_message_event_type = utils::indradb::Identifier::new("emote_message_event")?;
+        let image_message_event_type = utils::indradb::Identifier::new("image_message_event")?;
+        let file_message_event_type = utils::indradb::Identifier::new("file_message_event")?;
+        let video_message_event_type = utils::indradb::Identifier::new("video_message_event")?;
+        let audio_message_event_type = utils::indradb::Identifier::new("audio_message_event")?;
+        let location_message_event_type =
+            utils::indradb::Identifier::new("location_message_event")?;
         let event_id_type = utils::indradb::Identifier::new("event_id")?;
+        let origin_server_ts_type = utils::indradb::Identifier::new("origin_server_ts")?;
+        let sender_type = utils::indradb::Identifier::new("sender")?;
         let event_in_room_type = utils::indradb::Identifier::new("event_in_room")?;
+        let user_type = utils::indradb::Identifier::new("matrix_user")?;
+        let user_id_type = utils::indradb::Identifier::new("user_id")?;
+        let user_display_name_type = utils::indradb::Identifier::new("user_display_name")?;
+        let user_avatar_url_type = utils::indradb::Identifier::new("user_avatar_url")?;
+        let sent_by_type = utils::indradb::Identifier::new("sent_by")?;
+        let placeholder_event_type = utils::indradb::Identifier::new("placeholder_event")?;
+        let reply_to_type = utils::indradb::Identifier::new("reply_to")?;
+        let in_thread_type = utils::indradb::Identifier::new("in_thread")?;
+        let revision_type = utils::indradb::Identifier::new("revision")?;
+        let revision_event_id_type = utils::indradb::Identifier::new("revision_event_id")?;
+        let revision_of_type = utils::indradb::Identifier::new("revision_of")?;
+        let edited_at_type = utils::indradb::Identifier::new("edited_at")?;
+        let redacted_type = utils::indradb::Identifier::new("redacted")?;
+        let reacted_with_type = utils::indradb::Identifier::new("reacted_with")?;
+        let reaction_keys_type = utils::indradb::Identifier::new("reaction_keys")?;
+        let reaction_count_type = utils::indradb::Identifier::new("reaction_count")?;
+        let reaction_events_type = utils::indradb::Identifier::new("reaction_events")?;
+        let reaction_vertex_type = utils::indradb::Identifier::new("reaction_event")?;
+        let reacted_by_type = utils::indradb::Identifier::new("reacted_by")?;
+        let reacted_to_type = utils::indradb::Identifier::new("reacted_to")?;
+        let link_type = utils::indradb::Identifier::new("link")?;
+        let link_url_type = utils::indradb::Identifier::new("link_url")?;
+        let mentions_link_type = utils::indradb::Identifier::new("mentions_link")?;
+        let mentions_user_type = utils::indradb::Identifier::new("mentions_user")?;
+        let mentions_room_type = utils::indradb::Identifier::new("mentions_room")?;
+        let links_to_event_type = utils::indradb::Identifier::new("links_to_event")?;
         indexer_client.index_property(room_id_type).await?;
         indexer_client.index_property(room_name_type).await?;
         indexer_client.index_property(room_topic_type).await?;
+        indexer_client
+            .index_property(room_canonical_alias_type)
+            .await?;
         indexer_client.index_property(event_id_type).await?;
+        indexer_client.index_property(sender_type).await?;
+        indexer_client.index_property(user_id_type).await?;
+        indexer_client
+            .index_property(revision_event_id_type)
+            .await?;
+        indexer_client.index_property(link_url_type).await?;
         indexer_client
             .index_property(utils::indradb::Identifier::new("text_message_body")?)
             .await?;
@@ -75,6 +283,9 @@ impl IndexerBot {
                 "text_message_formatted_body",
             )?)
             .await?;
+        indexer_client
+            .index_property(utils::indradb::Identifier::new("image_hash")?)
+            .await?;
         info!("Connected to indradb");
 
         Ok((
@@ -84,10 +295,54 @@ impl IndexerBot {
                 room_id_type,
                 room_name_type,
                 room_topic_type,
+                room_canonical_alias_type,
+                room_avatar_url_type,
+                room_name_change_type,
+                name_change_of_type,
+                space_contains_type,
+                order_type,
+                suggested_type,
+                member_of_type,
+                membership_type,
+                joined_at_type,
                 text_message_event_type,
                 notice_message_event_type,
+                emote_message_event_type,
+                image_message_event_type,
+                file_message_event_type,
+                video_message_event_type,
+                audio_message_event_type,
+                location_message_event_type,
                 event_id_type,
+                origin_server_ts_type,
+                sender_type,
                 event_in_room_type,
+                user_type,
+                user_id_type,
+                user_display_name_type,
+                user_avatar_url_type,
+                sent_by_type,
+                placeholder_event_type,
+                reply_to_type,
+                in_thread_type,
+                revision_type,
+                revision_event_id_type,
+                revision_of_type,
+                edited_at_type,
+                redacted_type,
+                reacted_with_type,
+                reaction_keys_type,
+                reaction_count_type,
+                reaction_events_type,
+                reaction_vertex_type,
+                reacted_by_type,
+                reacted_to_type,
+                link_type,
+                link_url_type,
+                mentions_link_type,
+                mentions_user_type,
+                mentions_room_type,
+                links_to_event_type,
             },
         ))
     }
@@ -97,6 +352,8 @@ impl IndexerBot {
         user_id: String,
         password: String,
         indra_endpoint: String,
+        max_attachment_size: u64,
+        backfill: Option<BackfillLimits>,
     ) -> Result<Self> {
         let client = IndexerBot::get_client(homeserver_url).await?;
         client
@@ -107,20 +364,20 @@ impl IndexerBot {
 
         let (indexer_client, identifiers) = IndexerBot::get_indexer_client(indra_endpoint).await?;
 
-        let client_clone = client.clone();
-        tokio::spawn(async move {
-            let settings = SyncSettings::default();
-            client_clone
-                .sync(settings)
-                .await
-                .expect("Failed to start matrix sync");
-        });
-
         Ok(IndexerBot {
             client,
             indexer_client,
             message_map: MessagesMap::default(),
             identifiers,
+            text_index: TextIndex::load(utils::get_text_index_path())?,
+            max_attachment_size,
+            http_client: reqwest::Client::new(),
+            room_aliases: AliasCache::default(),
+            backfill,
+            backfill_queue: None,
+            checkpoints: Checkpoints::load(PathBuf::from(backfill::CHECKPOINTS_PATH))?,
+            prev_batches: BTreeMap::new(),
+            sync_token: SyncToken::load(PathBuf::from(sync_token::SYNC_TOKEN_PATH))?,
         })
     }
 
@@ -130,6 +387,8 @@ impl IndexerBot {
         access_token: String,
         device_id: String,
         indra_endpoint: String,
+        max_attachment_size: u64,
+        backfill: Option<BackfillLimits>,
     ) -> Result<Self> {
         let client = IndexerBot::get_client(homeserver_url).await?;
         client
@@ -143,183 +402,2128 @@ impl IndexerBot {
 
         let (indexer_client, identifiers) = IndexerBot::get_indexer_client(indra_endpoint).await?;
 
-        let client_clone = client.clone();
-        tokio::spawn(async move {
-            let settings = SyncSettings::default();
-            client_clone
-                .sync(settings)
-                .await
-                .expect("Failed to start matrix sync");
-        });
-
         Ok(IndexerBot {
             client,
             indexer_client,
             message_map: MessagesMap::default(),
             identifiers,
+            text_index: TextIndex::load(utils::get_text_index_path())?,
+            max_attachment_size,
+            http_client: reqwest::Client::new(),
+            room_aliases: AliasCache::default(),
+            backfill,
+            backfill_queue: None,
+            checkpoints: Checkpoints::load(PathBuf::from(backfill::CHECKPOINTS_PATH))?,
+            prev_batches: BTreeMap::new(),
+            sync_token: SyncToken::load(PathBuf::from(sync_token::SYNC_TOKEN_PATH))?,
         })
     }
 
-    // FIXME:_split into multiple functions
+    /// Checks a space parent claimed by a room against the state of the space.
+    ///
+    /// Anyone able to send state in a room can claim any space as its parent. The claim only
+    /// counts if the space lists the room as a child, or if the parent is canonical and was set by
+    /// someone allowed to add children to the space. Spaces we are not in can't be checked.
+    async fn is_confirmed_space_parent(
+        &self,
+        room_id: &RoomId,
+        event: &OriginalSyncSpaceParentEvent,
+    ) -> bool {
+        let Some(space) = self.client.get_joined_room(&event.state_key) else {
+            return false;
+        };
+
+        match space
+            .get_state_event(StateEventType::SpaceChild, room_id.as_str())
+            .await
+        {
+            Ok(Some(raw)) => {
+                if let Ok(AnySyncStateEvent::SpaceChild(SyncStateEvent::Original(child))) =
+                    raw.deserialize()
+                {
+                    if child.content.via.is_some_and(|via| !via.is_empty()) {
+                        return true;
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Error getting space child of {}: {}", event.state_key, e);
+                return false;
+            }
+        }
+
+        if !event.content.canonical {
+            return false;
+        }
+        match space
+            .get_state_event(StateEventType::RoomPowerLevels, "")
+            .await
+        {
+            Ok(Some(raw)) => match raw.deserialize() {
+                Ok(AnySyncStateEvent::RoomPowerLevels(SyncStateEvent::Original(power_levels))) => {
+                    RoomPowerLevels::from(power_levels.content).user_can_do(
+                        &event.sender,
+                        PowerLevelAction::SendState(StateEventType::SpaceChild),
+                    )
+                }
+                _ => false,
+            },
+            Ok(None) => false,
+            Err(e) => {
+                error!("Error getting power levels of {}: {}", event.state_key, e);
+                false
+            }
+        }
+    }
+
+    /// Downloads media within the configured size limit. A limit of 0 downloads nothing.
+    ///
+    /// Encrypted media is skipped as we can't decrypt it.
+    async fn download(&self, source: &MediaSource, thumbnail: bool) -> Option<Vec<u8>> {
+        if self.max_attachment_size == 0 {
+            return None;
+        }
+        let MediaSource::Plain(mxc_uri) = source else {
+            return None;
+        };
+        let homeserver = self.client.homeserver().await;
+        let url = if thumbnail {
+            attachments::thumbnail_url(&homeserver, mxc_uri)
+        } else {
+            attachments::download_url(&homeserver, mxc_uri)
+        };
+        let result = match url {
+            Ok(url) => {
+                attachments::download(&self.http_client, url, self.max_attachment_size).await
+            }
+            Err(e) => Err(e),
+        };
+        result.unwrap_or_else(|e| {
+            warn!("Failed to download {}: {}", media_url(source), e);
+            None
+        })
+    }
+
+    /// Downloads a file and extracts its text if it is a text, markdown or PDF file within the
+    /// configured size limit.
+    async fn attachment_text(&self, content: &FileMessageEventContent) -> Option<String> {
+        let info = content.info.as_deref();
+        // Skip files which announce to be too large before downloading anything
+        if info
+            .and_then(|info| info.size)
+            .is_some_and(|size| u64::from(size) > self.max_attachment_size)
+        {
+            return None;
+        }
+        let kind = AttachmentKind::detect(
+            info.and_then(|info| info.mimetype.as_deref()),
+            content.filename.as_deref().unwrap_or(&content.body),
+        )?;
+
+        let data = self.download(&content.source, false).await?;
+        match attachments::extract_text(kind, data).await {
+            Ok(text) if !text.is_empty() => Some(text),
+            Ok(_) => None,
+            Err(e) => {
+                warn!(
+                    "Failed to extract text of attachment {}: {}",
+                    media_url(&content.source),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Computes the perceptual hash of an image. A server generated thumbnail is enough for this
+    /// and far smaller than the image, so the full image is only downloaded without one.
+    async fn image_hash(&self, content: &ImageMessageEventContent) -> Option<String> {
+        let info = content.info.as_deref();
+        let data =
+            if let Some(thumbnail_source) = info.and_then(|info| info.thumbnail_source.as_ref()) {
+                self.download(thumbnail_source, true).await?
+            } else {
+                if info
+                    .and_then(|info| info.size)
+                    .is_some_and(|size| u64::from(size) > self.max_attachment_size)
+                {
+                    return None;
+                }
+                self.download(&content.source, false).await?
+            };
+
+        match attachments::image_hash(data).await {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                warn!("Failed to hash image {}: {}", media_url(&content.source), e);
+                None
+            }
+        }
+    }
+
+    /// Maps the message types we index to their vertex type and properties.
     #[allow(clippy::too_many_lines)]
-    pub async fn start_processing(&mut self) -> Result<()> {
-        let mut inserter = BulkInserter::new(self.indexer_client.clone());
+    fn message_properties(
+        &self,
+        msgtype: MessageType,
+    ) -> Option<(utils::indradb::Identifier, EventProperties)> {
+        match msgtype {
+            MessageType::Text(message_content) => Some((
+                self.identifiers.text_message_event_type,
+                EventProperties::TextMessage(
+                    message_content.body,
+                    message_content
+                        .formatted
+                        .clone()
+                        .map(|x| x.format.to_string()),
+                    message_content.formatted.map(|x| x.body),
+                ),
+            )),
+            MessageType::Notice(message_content) => Some((
+                self.identifiers.notice_message_event_type,
+                EventProperties::TextMessage(
+                    message_content.body,
+                    message_content
+                        .formatted
+                        .clone()
+                        .map(|x| x.format.to_string()),
+                    message_content.formatted.map(|x| x.body),
+                ),
+            )),
+            MessageType::Emote(message_content) => Some((
+                self.identifiers.emote_message_event_type,
+                EventProperties::TextMessage(
+                    message_content.body,
+                    message_content
+                        .formatted
+                        .clone()
+                        .map(|x| x.format.to_string()),
+                    message_content.formatted.map(|x| x.body),
+                ),
+            )),
+            MessageType::Image(message_content) => {
+                let info = message_content.info.as_deref();
+                Some((
+                    self.identifiers.image_message_event_type,
+                    EventProperties::Media(MediaProperties {
+                        mimetype: info.and_then(|info| info.mimetype.clone()),
+                        size: info.and_then(|info| info.size).map(u64::from),
+                        width: info.and_then(|info| info.width).map(u64::from),
+                        height: info.and_then(|info| info.height).map(u64::from),
+                        url: Some(media_url(&message_content.source)),
+                        body: message_content.body,
+                        ..MediaProperties::default()
+                    }),
+                ))
+            }
+            MessageType::File(message_content) => {
+                let info = message_content.info.as_deref();
+                Some((
+                    self.identifiers.file_message_event_type,
+                    EventProperties::Media(MediaProperties {
+                        mimetype: info.and_then(|info| info.mimetype.clone()),
+                        size: info.and_then(|info| info.size).map(u64::from),
+                        url: Some(media_url(&message_content.source)),
+                        filename: message_content.filename,
+                        body: message_content.body,
+                        ..MediaProperties::default()
+                    }),
+                ))
+            }
+            MessageType::Video(message_content) => {
+                let info = message_content.info.as_deref();
+                Some((
+                    self.identifiers.video_message_event_type,
+                    EventProperties::Media(MediaProperties {
+                        mimetype: info.and_then(|info| info.mimetype.clone()),
+                        size: info.and_then(|info| info.size).map(u64::from),
+                        width: info.and_then(|info| info.width).map(u64::from),
+                        height: info.and_then(|info| info.height).map(u64::from),
+                        duration: info.and_then(|info| info.duration).and_then(duration_ms),
+                        url: Some(media_url(&message_content.source)),
+                        body: message_content.body,
+                        ..MediaProperties::default()
+                    }),
+                ))
+            }
+            MessageType::Audio(message_content) => {
+                let info = message_content.info.as_deref();
+                Some((
+                    self.identifiers.audio_message_event_type,
+                    EventProperties::Media(MediaProperties {
+                        mimetype: info.and_then(|info| info.mimetype.clone()),
+                        size: info.and_then(|info| info.size).map(u64::from),
+                        duration: info.and_then(|info| info.duration).and_then(duration_ms),
+                        url: Some(media_url(&message_content.source)),
+                        body: message_content.body,
+                        ..MediaProperties::default()
+                    }),
+                ))
+            }
+            MessageType::Location(message_content) => Some((
+                self.identifiers.location_message_event_type,
+                EventProperties::Location(message_content.body, message_content.geo_uri),
+            )),
+            _ => None,
+        }
+    }
 
-        info!("Got bulk inserter. Starting sync");
+    /// Processes a single room message from the timeline.
+    async fn process_room_message(
+        &mut self,
+        room_id: &RoomId,
+        room_uuid: RoomUuid,
+        message: OriginalSyncRoomMessageEvent,
+        raw: &Raw<AnySyncTimelineEvent>,
+    ) {
+        self.message_map.insert_user(message.sender.clone());
+        // Intentional mentions are newer than the ruma version we use
+        let content: Option<serde_json::Value> = raw.get_field("content").ok().flatten();
 
-        let mut sync_stream = Box::pin(self.client.sync_stream(SyncSettings::default()).await);
+        // Edits update the original event instead of becoming events of their own. Their
+        // top-level content is only a fallback for clients without edit support.
+        if let Some(Relation::Replacement(replacement)) = message.content.relates_to {
+            if let Some((_, event_properties)) =
+                self.message_properties(replacement.new_content.msgtype)
+            {
+                let intentional = content
+                    .as_ref()
+                    .and_then(|content| content.get("m.new_content"))
+                    .and_then(|new_content| new_content.get("m.mentions"));
+                let mentions = self
+                    .mention_targets(room_uuid, &event_properties, intentional)
+                    .await;
+                self.message_map.insert_edit(UUIDEditMapType {
+                    event_id: message.event_id,
+                    original_event_id: replacement.event_id,
+                    room_id: room_id.to_owned(),
+                    event_properties,
+                    mentions,
+                    origin_server_ts: message.origin_server_ts,
+                    sender: message.sender,
+                });
+            }
+            return;
+        }
 
-        info!("Sync obtained. Starting to process sync stream");
-        while let Some(Ok(response)) = sync_stream.next().await {
-            for (ref room_id, room) in response.rooms.join {
-                for e in &room.timeline.events {
-                    let room_uuid = if let Some(room) = self.client.get_joined_room(room_id) {
-                        self.message_map.insert_room(
-                            room_id.clone(),
-                            crate::indradb_utils::RoomProperties {
-                                name: room.name(),
-                                topic: room.topic(),
-                            },
-                        )
-                    } else {
-                        self.message_map.insert_room(
-                            room_id.clone(),
-                            crate::indradb_utils::RoomProperties {
-                                name: None,
-                                topic: None,
-                            },
-                        )
-                    };
-
-                    match e.event.deserialize() {
-                        Ok(AnySyncTimelineEvent::MessageLike(
-                            AnySyncMessageLikeEvent::RoomMessage(event),
-                        )) => {
-                            if let SyncMessageLikeEvent::Original(message) = event {
-                                match message.content.msgtype {
-                                    MessageType::Text(message_content) => {
-                                        self.message_map.insert_event(
-                                            message.event_id,
-                                            room_uuid,
-                                            self.identifiers.text_message_event_type,
-                                            crate::indradb_utils::EventProperties::TextMessage(
-                                                message_content.body,
-                                                message_content
-                                                    .formatted
-                                                    .clone()
-                                                    .map(|x| x.format.to_string()),
-                                                message_content.formatted.map(|x| x.body),
-                                            ),
-                                        );
-                                    }
-                                    MessageType::Notice(message_content) => {
-                                        self.message_map.insert_event(
-                                            message.event_id,
-                                            room_uuid,
-                                            self.identifiers.notice_message_event_type,
-                                            crate::indradb_utils::EventProperties::TextMessage(
-                                                message_content.body,
-                                                message_content
-                                                    .formatted
-                                                    .clone()
-                                                    .map(|x| x.format.to_string()),
-                                                message_content.formatted.map(|x| x.body),
-                                            ),
-                                        );
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        }
-                        // TODO: index space hierachy
-                        Ok(
-                            AnySyncTimelineEvent::MessageLike(_) | AnySyncTimelineEvent::State(_),
-                        ) => {}
-                        Err(e) => {
-                            error!("Error deserializing event: {}", e);
-                        }
+        let (attachment_text, image_hash) = match &message.content.msgtype {
+            MessageType::File(content) => (self.attachment_text(content).await, None),
+            MessageType::Image(content) => (None, self.image_hash(content).await),
+            _ => (None, None),
+        };
+        let Some((event_type, mut event_properties)) =
+            self.message_properties(message.content.msgtype)
+        else {
+            return;
+        };
+        if let EventProperties::Media(media) = &mut event_properties {
+            media.attachment_text = attachment_text;
+            media.image_hash = image_hash;
+        }
+        let intentional = content
+            .as_ref()
+            .and_then(|content| content.get("m.mentions"));
+        let mentions = self
+            .mention_targets(room_uuid, &event_properties, intentional)
+            .await;
+        let event_uuid = self.message_map.insert_event(
+            message.event_id,
+            room_uuid,
+            event_type,
+            event_properties,
+            mentions,
+            message.origin_server_ts,
+            message.sender.clone(),
+        );
+
+        if let Some(relation) = message.content.relates_to {
+            match relation {
+                Relation::Reply { in_reply_to } => {
+                    self.message_map
+                        .insert_reply(event_uuid, in_reply_to.event_id);
+                }
+                Relation::Thread(thread) => {
+                    // When falling back the reply only points at the latest thread event for
+                    // clients without thread support.
+                    if !thread.is_falling_back {
+                        self.message_map
+                            .insert_reply(event_uuid, thread.in_reply_to.event_id);
                     }
+                    self.message_map.insert_thread(event_uuid, thread.event_id);
                 }
+                _ => {}
             }
+        }
+    }
 
-            // Push to indexer after we preprocessed it
-            for UUIDRoomMapType {
-                room_id,
-                uuid,
-                room_properties,
-            } in &self.message_map.room_list
-            {
-                inserter
-                    .push(utils::indradb::BulkInsertItem::Vertex(
-                        utils::indradb::Vertex::with_id(*uuid, self.identifiers.room_type),
-                    ))
-                    .await?;
-                inserter
-                    .push(utils::indradb::BulkInsertItem::VertexProperty(
-                        *uuid,
-                        self.identifiers.room_id_type,
-                        serde_json::Value::String(room_id.to_string()).into(),
-                    ))
+    /// Looks up the vertices a message refers to. Mentioned users and rooms we did not see yet
+    /// get a vertex of their own.
+    async fn mention_targets(
+        &mut self,
+        room_uuid: RoomUuid,
+        event_properties: &EventProperties,
+        intentional: Option<&serde_json::Value>,
+    ) -> MentionTargets {
+        let (body, html) = event_properties.body_and_html().unwrap_or_default();
+        let mentions = Mentions::extract(body, html, intentional, |alias| self.local_alias(alias));
+
+        let mut targets = MentionTargets::default();
+        for user_id in mentions.users {
+            targets.users.insert(self.message_map.insert_user(user_id));
+        }
+        for room in mentions.rooms {
+            let room_id = match OwnedRoomId::try_from(room) {
+                Ok(room_id) => Some(room_id),
+                Err(alias) => self.resolve_alias(alias).await,
+            };
+            if let Some(room_id) = room_id {
+                targets
+                    .rooms
+                    .insert(self.message_map.room_uuid_or_stub(room_id));
+            }
+        }
+        if mentions.room {
+            targets.rooms.insert(room_uuid);
+        }
+        for event_id in mentions.events {
+            targets
+                .events
+                .insert(self.message_map.event_uuid_or_placeholder(event_id));
+        }
+        targets
+    }
+
+    /// Finds the room of an alias without asking the homeserver, from earlier resolutions and
+    /// the canonical and alternative aliases of the rooms we are in.
+    fn local_alias(&self, alias: &RoomAliasId) -> Option<OwnedRoomId> {
+        if let Some(Some(room_id)) = self.room_aliases.get(alias) {
+            return Some(room_id.clone());
+        }
+        self.client
+            .rooms()
+            .into_iter()
+            .find(|room| {
+                room.canonical_alias().as_deref() == Some(alias)
+                    || room
+                        .alt_aliases()
+                        .iter()
+                        .any(|alt_alias| alt_alias == alias)
+            })
+            .map(|room| room.room_id().to_owned())
+    }
+
+    /// Resolves a room alias through the homeserver. Results are cached for a while as aliases
+    /// rarely move.
+    async fn resolve_alias(&mut self, alias: OwnedRoomAliasId) -> Option<OwnedRoomId> {
+        if let Some(room_id) = self.room_aliases.get(&alias) {
+            return room_id.clone();
+        }
+        let room_id = match self.client.resolve_room_alias(&alias).await {
+            Ok(response) => Some(response.room_id),
+            Err(e) => {
+                warn!("Failed to resolve room alias {}: {}", alias, e);
+                None
+            }
+        };
+        self.room_aliases.insert(alias, room_id.clone());
+        room_id
+    }
+
+    /// Processes a reaction to an event from the timeline.
+    fn process_reaction(&mut self, reaction: OriginalSyncReactionEvent) {
+        let user_uuid = self.message_map.insert_user(reaction.sender);
+        self.message_map.insert_reaction(
+            reaction.event_id,
+            user_uuid,
+            reaction.content.relates_to.event_id,
+            reaction.content.relates_to.key,
+        );
+    }
+
+    /// Processes a state event of a joined room from either the state or the timeline.
+    ///
+    /// Room state updates the room vertex, members are linked to the room and space children
+    /// and parents form the space hierarchy.
+    async fn process_state_event(
+        &mut self,
+        room_id: &RoomId,
+        room_uuid: RoomUuid,
+        event: &AnySyncStateEvent,
+    ) {
+        let update = match event {
+            AnySyncStateEvent::RoomName(SyncStateEvent::Original(event)) => {
+                RoomStateUpdate::Name(event.content.name.as_ref().map(ToString::to_string))
+            }
+            AnySyncStateEvent::RoomTopic(SyncStateEvent::Original(event)) => {
+                RoomStateUpdate::Topic(
+                    Some(event.content.topic.clone()).filter(|topic| !topic.is_empty()),
+                )
+            }
+            AnySyncStateEvent::RoomCanonicalAlias(SyncStateEvent::Original(event)) => {
+                RoomStateUpdate::CanonicalAlias(
+                    event.content.alias.as_ref().map(ToString::to_string),
+                )
+            }
+            AnySyncStateEvent::RoomAvatar(SyncStateEvent::Original(event)) => {
+                RoomStateUpdate::Avatar(event.content.url.as_ref().map(ToString::to_string))
+            }
+            AnySyncStateEvent::SpaceChild(SyncStateEvent::Original(event)) => {
+                let child_uuid = self.message_map.room_uuid_or_stub(event.state_key.clone());
+                // A child without servers to join it through was removed from the space
+                let child = event
+                    .content
+                    .via
+                    .as_ref()
+                    .is_some_and(|via| !via.is_empty())
+                    .then(|| SpaceChild {
+                        order: event.content.order.clone(),
+                        suggested: event.content.suggested.unwrap_or_default(),
+                    });
+                self.message_map.insert_space_child(
+                    room_uuid,
+                    child_uuid,
+                    child,
+                    event.origin_server_ts,
+                );
+                return;
+            }
+            AnySyncStateEvent::RoomMember(SyncStateEvent::Original(event)) => {
+                let user_uuid = self.message_map.update_user_profile(
+                    event.state_key.clone(),
+                    UserProperties {
+                        display_name: event.content.displayname.clone(),
+                        avatar_url: event.content.avatar_url.as_ref().map(ToString::to_string),
+                    },
+                    event.origin_server_ts,
+                );
+                self.message_map.insert_membership(
+                    user_uuid,
+                    room_uuid,
+                    event.content.membership.as_str().to_owned(),
+                    event.origin_server_ts,
+                );
+                return;
+            }
+            AnySyncStateEvent::SpaceParent(SyncStateEvent::Original(event)) => {
+                if event
+                    .content
+                    .via
+                    .as_ref()
+                    .is_some_and(|via| !via.is_empty())
+                    && self.is_confirmed_space_parent(room_id, event).await
+                {
+                    let space_uuid = self.message_map.room_uuid_or_stub(event.state_key.clone());
+                    self.message_map.insert_space_parent(space_uuid, room_uuid);
+                }
+                return;
+            }
+            _ => return,
+        };
+        self.message_map
+            .update_room(room_uuid, update, event.origin_server_ts());
+    }
+
+    /// Processes a single event from the timeline of a joined room.
+    async fn process_timeline_event(
+        &mut self,
+        room_id: &RoomId,
+        room_uuid: RoomUuid,
+        event: &Raw<AnySyncTimelineEvent>,
+    ) {
+        match event.deserialize() {
+            Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                SyncMessageLikeEvent::Original(message),
+            ))) => {
+                self.process_room_message(room_id, room_uuid, message, event)
+                    .await;
+            }
+            Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::Reaction(
+                SyncMessageLikeEvent::Original(reaction),
+            ))) => {
+                self.process_reaction(reaction);
+            }
+            Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomRedaction(
+                SyncRoomRedactionEvent::Original(redaction),
+            ))) => {
+                self.message_map.insert_redaction(redaction.redacts);
+            }
+            // Events which were already redacted when we received them
+            Ok(AnySyncTimelineEvent::MessageLike(event)) if event.original_content().is_none() => {
+                self.message_map
+                    .insert_redaction(event.event_id().to_owned());
+            }
+            Ok(AnySyncTimelineEvent::State(event)) => {
+                self.process_state_event(room_id, room_uuid, &event).await;
+            }
+            Ok(AnySyncTimelineEvent::MessageLike(_)) => {}
+            Err(e) => {
+                error!("Error deserializing event: {}", e);
+            }
+        }
+    }
+
+    /// Looks up a property of the given vertices. Vertices without the property are left out.
+    async fn vertex_property(
+        &mut self,
+        uuids: Vec<Uuid>,
+        name: utils::indradb::Identifier,
+    ) -> Result<HashMap<Uuid, serde_json::Value>> {
+        if uuids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let output = self
+            .indexer_client
+            .get(SpecificVertexQuery::new(uuids).properties()?.name(name))
+            .await?;
+        Ok(extract_vertex_properties(output)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|vertex_properties| {
+                let property = vertex_properties.props.into_iter().next()?;
+                Some((vertex_properties.vertex.id, (*property.value).clone()))
+            })
+            .collect())
+    }
+
+    /// Looks up who sent the events edited in `changes`, so edits by anyone else can be ignored.
+    ///
+    /// Originals which are neither part of this batch nor stored yet, which is common while
+    /// backfilling, are fetched from the homeserver. Edits of events whose sender stays unknown
+    /// are dropped.
+    async fn original_senders(&mut self, changes: &Changes) -> Result<HashMap<EventUuid, String>> {
+        let mut senders: HashMap<EventUuid, String> = changes
+            .edits
+            .keys()
+            .filter_map(|uuid| Some((*uuid, changes.message_list.get(uuid)?.sender.to_string())))
+            .collect();
+        let stored = self
+            .vertex_property(
+                changes
+                    .edits
+                    .keys()
+                    .filter(|uuid| !senders.contains_key(uuid))
+                    .copied()
+                    .collect(),
+                self.identifiers.sender_type,
+            )
+            .await?;
+        senders.extend(
+            stored
+                .into_iter()
+                .filter_map(|(uuid, sender)| Some((uuid, sender.as_str()?.to_string()))),
+        );
+
+        for (original_uuid, edits) in &changes.edits {
+            let Some(edit) = edits.first() else {
+                continue;
+            };
+            if senders.contains_key(original_uuid) {
+                continue;
+            }
+            let Some(room) = self.client.get_joined_room(&edit.room_id) else {
+                continue;
+            };
+            match room.event(&edit.original_event_id).await {
+                Ok(original) => {
+                    if let Ok(Some(sender)) = original.event.get_field::<String>("sender") {
+                        senders.insert(*original_uuid, sender);
+                    }
+                }
+                Err(e) => warn!(
+                    "Ignoring edits of {}, unable to fetch it: {}",
+                    edit.original_event_id, e
+                ),
+            }
+        }
+        Ok(senders)
+    }
+
+    /// Looks up the names a room had according to its stored name changes.
+    async fn stored_room_names(&mut self, room_uuid: RoomUuid) -> Result<BTreeSet<String>> {
+        let output = self
+            .indexer_client
+            .get(
+                SpecificVertexQuery::single(room_uuid)
+                    .inbound()?
+                    .t(self.identifiers.name_change_of_type)
+                    .outbound()?
+                    .properties()?
+                    .name(self.identifiers.room_name_type),
+            )
+            .await?;
+        Ok(extract_vertex_properties(output)
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|vertex_properties| vertex_properties.props)
+            .filter_map(|property| property.value.as_str().map(ToString::to_string))
+            .collect())
+    }
+
+    /// Looks up the types of the given vertices. Vertices which don't exist are left out.
+    async fn vertex_types(
+        &mut self,
+        uuids: Vec<Uuid>,
+    ) -> Result<HashMap<Uuid, utils::indradb::Identifier>> {
+        if uuids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let output = self
+            .indexer_client
+            .get(SpecificVertexQuery::new(uuids))
+            .await?;
+        Ok(extract_vertices(output)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|vertex| (vertex.id, vertex.t))
+            .collect())
+    }
+
+    /// Strips redacted events already stored in indradb down to their event id, timestamp and
+    /// sender and deletes their revisions. Redacted edits are deleted completely and the event
+    /// they edited falls back to its previous content. Reaction vertices lose what they reacted
+    /// to, which keeps the reaction from being counted again.
+    ///
+    /// Events we don't know yet are stored as redacted placeholders, so they never get indexed
+    /// with their content when a backfill comes across them later.
+    async fn apply_redactions<'a>(
+        &mut self,
+        redactions: impl Iterator<Item = &'a OwnedEventId>,
+    ) -> Result<()> {
+        for event_id in redactions {
+            let uuid = ids::event_uuid(event_id.as_str());
+            let revision_uuid = ids::revision_uuid(event_id.as_str());
+
+            let output = self
+                .indexer_client
+                .get(SpecificVertexQuery::single(uuid).properties()?)
+                .await?;
+            let Some(vertex_properties) = extract_vertex_properties(output)
+                .unwrap_or_default()
+                .into_iter()
+                .next()
+            else {
+                let output = self
+                    .indexer_client
+                    .get(SpecificVertexQuery::single(revision_uuid))
                     .await?;
-                if let Some(room_name) = &room_properties.name {
-                    inserter
-                        .push(utils::indradb::BulkInsertItem::VertexProperty(
-                            *uuid,
-                            self.identifiers.room_name_type,
-                            serde_json::Value::String(room_name.to_string()).into(),
+                if extract_vertices(output).unwrap_or_default().is_empty() {
+                    self.indexer_client
+                        .create_vertex(&utils::indradb::Vertex::with_id(
+                            uuid,
+                            self.identifiers.placeholder_event_type,
                         ))
                         .await?;
-                }
-                if let Some(room_topic) = &room_properties.topic {
-                    inserter
-                        .push(utils::indradb::BulkInsertItem::VertexProperty(
-                            *uuid,
-                            self.identifiers.room_topic_type,
-                            serde_json::Value::String(room_topic.to_string()).into(),
-                        ))
+                    self.indexer_client
+                        .set_properties(
+                            SpecificVertexQuery::single(uuid),
+                            self.identifiers.event_id_type,
+                            &Json::new(serde_json::Value::String(event_id.to_string())),
+                        )
+                        .await?;
+                    self.indexer_client
+                        .set_properties(
+                            SpecificVertexQuery::single(uuid),
+                            self.identifiers.redacted_type,
+                            &Json::new(serde_json::Value::Bool(true)),
+                        )
                         .await?;
+                } else {
+                    self.remove_revision(revision_uuid).await?;
                 }
+                continue;
+            };
+
+            for property in vertex_properties.props {
+                if KEPT_ON_REDACTION.contains(&property.name.as_str()) {
+                    continue;
+                }
+                self.indexer_client
+                    .delete(
+                        SpecificVertexQuery::single(uuid)
+                            .properties()?
+                            .name(property.name),
+                    )
+                    .await?;
             }
-            for UUIDEventMapType {
-                event_id,
-                uuid,
-                event_type,
-                event_properties,
-            } in &self.message_map.message_list
-            {
+            self.indexer_client
+                .set_properties(
+                    SpecificVertexQuery::single(uuid),
+                    self.identifiers.redacted_type,
+                    &Json::new(serde_json::Value::Bool(true)),
+                )
+                .await?;
+            self.indexer_client
+                .delete(
+                    SpecificVertexQuery::single(uuid)
+                        .inbound()?
+                        .t(self.identifiers.revision_of_type)
+                        .outbound()?,
+                )
+                .await?;
+            self.delete_mention_edges(uuid).await?;
+            self.text_index.remove_document(uuid);
+        }
+        Ok(())
+    }
+
+    /// Deletes the revision of a redacted edit.
+    ///
+    /// If the edit is what the edited event currently shows, the event gets the content of the
+    /// newest remaining revision back together with its links and mentions. Intentional mentions
+    /// are not stored, so only the ones found in the text are restored.
+    async fn remove_revision(&mut self, revision_uuid: Uuid) -> Result<()> {
+        let output = self
+            .indexer_client
+            .get(SpecificVertexQuery::single(revision_uuid).properties()?)
+            .await?;
+        let redacted_ts = extract_vertex_properties(output)
+            .unwrap_or_default()
+            .first()
+            .and_then(|vertex_properties| {
+                StoredRevision::from_properties(&property_map(vertex_properties))
+            })
+            .map(|revision| revision.origin_server_ts);
+        let output = self
+            .indexer_client
+            .get(
+                SpecificVertexQuery::single(revision_uuid)
+                    .outbound()?
+                    .t(self.identifiers.revision_of_type),
+            )
+            .await?;
+        let original_uuid = extract_edges(output)
+            .unwrap_or_default()
+            .first()
+            .map(|edge| edge.inbound_id);
+        self.indexer_client
+            .delete(SpecificVertexQuery::single(revision_uuid))
+            .await?;
+        match (redacted_ts, original_uuid) {
+            (Some(redacted_ts), Some(original_uuid)) => {
+                self.restore_previous_revision(original_uuid, redacted_ts)
+                    .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Puts the content of the newest remaining revision back on an edited event, unless the
+    /// event shows an edit other than the redacted one sent at `redacted_ts`.
+    async fn restore_previous_revision(
+        &mut self,
+        original_uuid: EventUuid,
+        redacted_ts: u64,
+    ) -> Result<()> {
+        let output = self
+            .indexer_client
+            .get(SpecificVertexQuery::single(original_uuid).properties()?)
+            .await?;
+        let Some(original) = extract_vertex_properties(output)
+            .unwrap_or_default()
+            .first()
+            .map(property_map)
+        else {
+            return Ok(());
+        };
+        let shown = original
+            .get("edited_at")
+            .and_then(serde_json::Value::as_u64)
+            .is_some_and(|edited_at| edited_at == redacted_ts);
+        let current = EventProperties::from_properties(&original);
+        if !shown || matches!(current, None | Some(EventProperties::Redacted)) {
+            return Ok(());
+        }
+
+        let output = self
+            .indexer_client
+            .get(
+                SpecificVertexQuery::single(original_uuid)
+                    .inbound()?
+                    .t(self.identifiers.revision_of_type)
+                    .outbound()?
+                    .properties()?,
+            )
+            .await?;
+        let Some(previous) = StoredRevision::newest(
+            extract_vertex_properties(output)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|vertex_properties| {
+                    StoredRevision::from_properties(&property_map(vertex_properties))
+                }),
+        ) else {
+            return Ok(());
+        };
+
+        let output = self
+            .indexer_client
+            .get(
+                SpecificVertexQuery::single(original_uuid)
+                    .outbound()?
+                    .t(self.identifiers.event_in_room_type),
+            )
+            .await?;
+        let mentions = match extract_edges(output).unwrap_or_default().first() {
+            Some(edge) => {
+                self.mention_targets(edge.inbound_id, &previous.event_properties, None)
+                    .await
+            }
+            None => MentionTargets::default(),
+        };
+        for name in original.keys() {
+            if EVENT_METADATA.contains(&name.as_str()) {
+                continue;
+            }
+            self.indexer_client
+                .delete(
+                    SpecificVertexQuery::single(original_uuid)
+                        .properties()?
+                        .name(utils::indradb::Identifier::new(name.as_str())?),
+                )
+                .await?;
+        }
+        self.delete_mention_edges(original_uuid).await?;
+        let mut items = self.content_items(original_uuid, &previous.event_properties, &mentions)?;
+        // Back at the original content the event counts as never edited
+        if original.get("event_id").and_then(serde_json::Value::as_str)
+            == Some(previous.event_id.as_str())
+        {
+            self.indexer_client
+                .delete(
+                    SpecificVertexQuery::single(original_uuid)
+                        .properties()?
+                        .name(self.identifiers.edited_at_type),
+                )
+                .await?;
+        } else {
+            items.push(utils::indradb::BulkInsertItem::VertexProperty(
+                original_uuid,
+                self.identifiers.edited_at_type,
+                serde_json::Value::from(previous.origin_server_ts).into(),
+            ));
+        }
+        self.indexer_client.bulk_insert(items).await?;
+        self.text_index.replace_fields(
+            original_uuid,
+            current.iter().flat_map(EventProperties::searchable_text),
+            previous.event_properties.searchable_text(),
+        );
+        Ok(())
+    }
+
+    /// Deletes the links and mentions of an event.
+    async fn delete_mention_edges(&mut self, uuid: EventUuid) -> Result<()> {
+        for edge_type in [
+            self.identifiers.mentions_link_type,
+            self.identifiers.mentions_user_type,
+            self.identifiers.mentions_room_type,
+            self.identifiers.links_to_event_type,
+        ] {
+            self.indexer_client
+                .delete(SpecificVertexQuery::single(uuid).outbound()?.t(edge_type))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The `mentions_user`, `mentions_room` and `links_to_event` edges of an event.
+    fn mention_edges(
+        &self,
+        event_uuid: EventUuid,
+        mentions: &MentionTargets,
+    ) -> Vec<utils::indradb::Edge> {
+        let users = mentions
+            .users
+            .iter()
+            .map(|uuid| (self.identifiers.mentions_user_type, uuid));
+        let rooms = mentions
+            .rooms
+            .iter()
+            .map(|uuid| (self.identifiers.mentions_room_type, uuid));
+        let events = mentions
+            .events
+            .iter()
+            .map(|uuid| (self.identifiers.links_to_event_type, uuid));
+        users
+            .chain(rooms)
+            .chain(events)
+            .map(|(edge_type, uuid)| utils::indradb::Edge::new(event_uuid, edge_type, *uuid))
+            .collect()
+    }
+
+    /// The content of an event together with the links and mentions it refers to.
+    fn content_items(
+        &self,
+        uuid: EventUuid,
+        event_properties: &EventProperties,
+        mentions: &MentionTargets,
+    ) -> Result<Vec<utils::indradb::BulkInsertItem>> {
+        let mut items = event_properties.as_vec(uuid)?;
+        for url in event_properties.links() {
+            let link_uuid = ids::link_uuid(&url);
+            items.push(utils::indradb::BulkInsertItem::Vertex(
+                utils::indradb::Vertex::with_id(link_uuid, self.identifiers.link_type),
+            ));
+            items.push(utils::indradb::BulkInsertItem::VertexProperty(
+                link_uuid,
+                self.identifiers.link_url_type,
+                serde_json::Value::String(url).into(),
+            ));
+            items.push(utils::indradb::BulkInsertItem::Edge(
+                utils::indradb::Edge::new(uuid, self.identifiers.mentions_link_type, link_uuid),
+            ));
+        }
+        items.extend(
+            self.mention_edges(uuid, mentions)
+                .into_iter()
+                .map(utils::indradb::BulkInsertItem::Edge),
+        );
+        Ok(items)
+    }
+
+    /// A revision vertex holding an earlier or later version of the content of an event.
+    fn revision_items(
+        &self,
+        revision: &UUIDRevisionMapType,
+    ) -> Result<Vec<utils::indradb::BulkInsertItem>> {
+        let mut items = vec![
+            utils::indradb::BulkInsertItem::Vertex(utils::indradb::Vertex::with_id(
+                revision.uuid,
+                self.identifiers.revision_type,
+            )),
+            utils::indradb::BulkInsertItem::VertexProperty(
+                revision.uuid,
+                self.identifiers.revision_event_id_type,
+                serde_json::Value::String(revision.event_id.to_string()).into(),
+            ),
+            utils::indradb::BulkInsertItem::VertexProperty(
+                revision.uuid,
+                self.identifiers.origin_server_ts_type,
+                serde_json::Value::from(u64::from(revision.origin_server_ts.0)).into(),
+            ),
+            utils::indradb::BulkInsertItem::Edge(utils::indradb::Edge::new(
+                revision.uuid,
+                self.identifiers.revision_of_type,
+                revision.revision_of,
+            )),
+        ];
+        items.extend(revision.event_properties.as_vec(revision.uuid)?);
+        Ok(items)
+    }
+
+    /// Keeps the content of events stored before their first edit as a revision.
+    ///
+    /// The content is copied from indradb as we don't keep events in memory once pushed.
+    async fn copy_original_revisions(
+        &mut self,
+        uuids: Vec<EventUuid>,
+        inserter: &mut BulkInserter,
+    ) -> Result<()> {
+        if uuids.is_empty() {
+            return Ok(());
+        }
+        let output = self
+            .indexer_client
+            .get(SpecificVertexQuery::new(uuids).properties()?)
+            .await?;
+        for vertex_properties in extract_vertex_properties(output).unwrap_or_default() {
+            let value_of = |name: &str| {
+                vertex_properties
+                    .props
+                    .iter()
+                    .find(|property| property.name.as_str() == name)
+                    .map(|property| (*property.value).clone())
+            };
+            let Some(event_id) = value_of("event_id")
+                .and_then(|event_id| event_id.as_str().map(ToString::to_string))
+            else {
+                continue;
+            };
+            let uuid = ids::revision_uuid(&event_id);
+            inserter
+                .push(utils::indradb::BulkInsertItem::Vertex(
+                    utils::indradb::Vertex::with_id(uuid, self.identifiers.revision_type),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    uuid,
+                    self.identifiers.revision_event_id_type,
+                    serde_json::Value::String(event_id).into(),
+                ))
+                .await?;
+            for property in &vertex_properties.props {
+                if EVENT_METADATA.contains(&property.name.as_str()) {
+                    continue;
+                }
                 inserter
-                    .push(utils::indradb::BulkInsertItem::Vertex(
-                        utils::indradb::Vertex::with_id(*uuid, *event_type),
+                    .push(utils::indradb::BulkInsertItem::VertexProperty(
+                        uuid,
+                        property.name,
+                        (*property.value).clone().into(),
                     ))
                     .await?;
+            }
+            if let Some(origin_server_ts) = value_of("origin_server_ts") {
                 inserter
                     .push(utils::indradb::BulkInsertItem::VertexProperty(
-                        *uuid,
-                        self.identifiers.event_id_type,
-                        serde_json::Value::String(event_id.to_string()).into(),
+                        uuid,
+                        self.identifiers.origin_server_ts_type,
+                        origin_server_ts.into(),
                     ))
                     .await?;
-                for event_property in event_properties
-                    .as_vec(*uuid)
-                    .expect("Unable to convert to indradb properties")
-                {
-                    inserter.push(event_property).await?;
+            }
+            inserter
+                .push(utils::indradb::BulkInsertItem::Edge(
+                    utils::indradb::Edge::new(
+                        uuid,
+                        self.identifiers.revision_of_type,
+                        vertex_properties.vertex.id,
+                    ),
+                ))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Reads the reactions stored on `reacted_with` edges, keyed by the event id of each
+    /// reaction.
+    fn stored_reactions(output: Vec<utils::indradb::QueryOutputValue>) -> Reactions {
+        extract_edge_properties(output)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|edge_properties| {
+                let reactions = edge_properties
+                    .props
+                    .first()
+                    .and_then(|property| property.value.as_object())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|(event_id, key)| Some((event_id.clone(), key.as_str()?.into())))
+                    .collect();
+                let edge = edge_properties.edge;
+                ((edge.outbound_id, edge.inbound_id), reactions)
+            })
+            .collect()
+    }
+
+    /// A vertex for a single reaction, so a redaction can find the reaction by its event id
+    /// whoever redacts it.
+    fn reaction_items(
+        &self,
+        reaction_uuid: EventUuid,
+        event_id: &OwnedEventId,
+        user_uuid: UserUuid,
+        event_uuid: EventUuid,
+    ) -> [utils::indradb::BulkInsertItem; 4] {
+        [
+            utils::indradb::BulkInsertItem::Vertex(utils::indradb::Vertex::with_id(
+                reaction_uuid,
+                self.identifiers.reaction_vertex_type,
+            )),
+            utils::indradb::BulkInsertItem::VertexProperty(
+                reaction_uuid,
+                self.identifiers.event_id_type,
+                serde_json::Value::String(event_id.to_string()).into(),
+            ),
+            utils::indradb::BulkInsertItem::VertexProperty(
+                reaction_uuid,
+                self.identifiers.reacted_by_type,
+                serde_json::Value::String(user_uuid.to_string()).into(),
+            ),
+            utils::indradb::BulkInsertItem::VertexProperty(
+                reaction_uuid,
+                self.identifiers.reacted_to_type,
+                serde_json::Value::String(event_uuid.to_string()).into(),
+            ),
+        ]
+    }
+
+    /// The `reacted_with` edges of stored reactions which got redacted in `changes`.
+    ///
+    /// Moderators redact reactions as well, so they are looked up by their own event id rather
+    /// than by who sent the redaction.
+    async fn redacted_reactions(
+        &mut self,
+        changes: &Changes,
+    ) -> Result<BTreeSet<(UserUuid, EventUuid)>> {
+        let uuids: Vec<Uuid> = changes
+            .redactions
+            .iter()
+            .map(|event_id| ids::event_uuid(event_id.as_str()))
+            .collect();
+        let reacted_by = self
+            .vertex_property(uuids.clone(), self.identifiers.reacted_by_type)
+            .await?;
+        let reacted_to = self
+            .vertex_property(uuids, self.identifiers.reacted_to_type)
+            .await?;
+        Ok(reacted_by
+            .into_iter()
+            .filter_map(|(uuid, user_uuid)| {
+                let user_uuid = user_uuid.as_str()?.parse().ok()?;
+                let event_uuid = reacted_to.get(&uuid)?.as_str()?.parse().ok()?;
+                Some((user_uuid, event_uuid))
+            })
+            .collect())
+    }
+
+    /// How many users reacted to an event with each key. Every key counts once per user,
+    /// however often they reacted with it.
+    fn reaction_counts(reactions: &Reactions, event_uuid: EventUuid) -> BTreeMap<String, u64> {
+        let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+        for ((_, reacted_to), edge_reactions) in reactions {
+            if *reacted_to != event_uuid {
+                continue;
+            }
+            for key in edge_reactions.values().collect::<BTreeSet<_>>() {
+                *counts.entry(key.clone()).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    /// Merges new and redacted reactions with the reactions stored in indradb and writes the
+    /// resulting `reacted_with` edges, reaction counts and the reaction keys in the text index.
+    #[allow(clippy::too_many_lines)]
+    async fn push_reactions(
+        &mut self,
+        changes: &Changes,
+        redacted: &HashSet<Uuid>,
+        inserter: &mut BulkInserter,
+    ) -> Result<ReactionUpdate> {
+        let mut update = ReactionUpdate::default();
+        let new_edges: BTreeSet<(UserUuid, EventUuid)> = changes
+            .reactions
+            .values()
+            .map(|(user_uuid, event_uuid, _)| (*user_uuid, *event_uuid))
+            .collect();
+        let mut edges = new_edges.clone();
+        edges.extend(self.redacted_reactions(changes).await?);
+
+        let mut stored = Reactions::new();
+        if !edges.is_empty() {
+            let edges = edges
+                .iter()
+                .map(|(user_uuid, event_uuid)| {
+                    utils::indradb::Edge::new(
+                        *user_uuid,
+                        self.identifiers.reacted_with_type,
+                        *event_uuid,
+                    )
+                })
+                .collect();
+            let output = self
+                .indexer_client
+                .get(
+                    SpecificEdgeQuery::new(edges)
+                        .properties()?
+                        .name(self.identifiers.reaction_events_type),
+                )
+                .await?;
+            stored.extend(Self::stored_reactions(output));
+        }
+
+        let redacted_ids: BTreeSet<&str> =
+            changes.redactions.iter().map(|id| id.as_str()).collect();
+        let mut touched = new_edges;
+        for (edge, edge_reactions) in &stored {
+            for event_id in edge_reactions.keys() {
+                if redacted_ids.contains(event_id.as_str()) {
+                    touched.insert(*edge);
                 }
             }
+        }
+        if touched.is_empty() {
+            return Ok(update);
+        }
+
+        // Counting needs the reactions of everyone else to the same events as well
+        let events: BTreeSet<EventUuid> =
+            touched.iter().map(|(_, event_uuid)| *event_uuid).collect();
+        let output = self
+            .indexer_client
+            .get(
+                SpecificVertexQuery::new(events.iter().copied().collect())
+                    .inbound()?
+                    .t(self.identifiers.reacted_with_type)
+                    .properties()?
+                    .name(self.identifiers.reaction_events_type),
+            )
+            .await?;
+        for (edge, edge_reactions) in Self::stored_reactions(output) {
+            stored.entry(edge).or_insert(edge_reactions);
+        }
+
+        let mut reactions = stored.clone();
+        for edge_reactions in reactions.values_mut() {
+            edge_reactions.retain(|event_id, _| !redacted_ids.contains(event_id.as_str()));
+        }
+        for (event_id, (user_uuid, event_uuid, key)) in &changes.reactions {
+            let reaction_uuid = ids::event_uuid(event_id.as_str());
+            if redacted.contains(&reaction_uuid) {
+                continue;
+            }
+            for item in self.reaction_items(reaction_uuid, event_id, *user_uuid, *event_uuid) {
+                inserter.push(item).await?;
+            }
+            reactions
+                .entry((*user_uuid, *event_uuid))
+                .or_default()
+                .insert(event_id.to_string(), key.clone());
+        }
+
+        for &(user_uuid, event_uuid) in &touched {
+            let edge_reactions = reactions
+                .get(&(user_uuid, event_uuid))
+                .cloned()
+                .unwrap_or_default();
+            if edge_reactions.is_empty() {
+                update.removed.push((user_uuid, event_uuid));
+                continue;
+            }
+            let keys: BTreeSet<String> = edge_reactions.values().cloned().collect();
+            let reacted_with = || {
+                utils::indradb::Edge::new(user_uuid, self.identifiers.reacted_with_type, event_uuid)
+            };
+            inserter
+                .push(utils::indradb::BulkInsertItem::Edge(reacted_with()))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::EdgeProperty(
+                    reacted_with(),
+                    self.identifiers.reaction_keys_type,
+                    serde_json::Value::from(keys.into_iter().collect::<Vec<_>>()).into(),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::EdgeProperty(
+                    reacted_with(),
+                    self.identifiers.reaction_events_type,
+                    serde_json::Value::Object(
+                        edge_reactions
+                            .into_iter()
+                            .map(|(event_id, key)| (event_id, key.into()))
+                            .collect(),
+                    )
+                    .into(),
+                ))
+                .await?;
+        }
+
+        for event_uuid in events {
+            let previous = Self::reaction_counts(&stored, event_uuid);
+            let counts = Self::reaction_counts(&reactions, event_uuid);
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    event_uuid,
+                    self.identifiers.reaction_count_type,
+                    serde_json::Value::from(counts.values().sum::<u64>()).into(),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    event_uuid,
+                    self.identifiers.reaction_keys_type,
+                    serde_json::Value::from(counts.keys().cloned().collect::<Vec<_>>()).into(),
+                ))
+                .await?;
+            // Reactions make the event searchable by emoji
+            self.text_index.replace_fields(
+                event_uuid,
+                previous.keys().map(String::as_str),
+                counts.keys().map(String::as_str),
+            );
+            update.keys.insert(event_uuid, counts.into_keys().collect());
+        }
+        Ok(update)
+    }
+
+    /// Pushes everything preprocessed since the last push to indradb and the text index.
+    #[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
+    async fn push_to_indexer(&mut self, inserter: &mut BulkInserter) -> Result<()> {
+        let changes = self.message_map.take_changes();
 
-            for (event_uuid, room_uuid) in &self.message_map.room_event_links {
+        for UUIDRoomMapType {
+            room_id,
+            uuid,
+            room_properties,
+            names,
+        } in changes.rooms.values()
+        {
+            inserter
+                .push(utils::indradb::BulkInsertItem::Vertex(
+                    utils::indradb::Vertex::with_id(*uuid, self.identifiers.room_type),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    *uuid,
+                    self.identifiers.room_id_type,
+                    serde_json::Value::String(room_id.to_string()).into(),
+                ))
+                .await?;
+            // A stub must not clear the properties of a room indexed before a restart
+            if self.message_map.is_stub(*uuid) {
+                continue;
+            }
+            // Removed state is written as null so it doesn't stick around in indradb
+            for (property, value) in [
+                (self.identifiers.room_name_type, &room_properties.name),
+                (self.identifiers.room_topic_type, &room_properties.topic),
+                (
+                    self.identifiers.room_canonical_alias_type,
+                    &room_properties.canonical_alias,
+                ),
+                (
+                    self.identifiers.room_avatar_url_type,
+                    &room_properties.avatar_url,
+                ),
+            ] {
                 inserter
-                    .push(utils::indradb::BulkInsertItem::Edge(
-                        utils::indradb::Edge::new(
-                            *event_uuid,
-                            self.identifiers.event_in_room_type,
-                            *room_uuid,
-                        ),
+                    .push(utils::indradb::BulkInsertItem::VertexProperty(
+                        *uuid,
+                        property,
+                        value
+                            .clone()
+                            .map_or(serde_json::Value::Null, serde_json::Value::String)
+                            .into(),
                     ))
                     .await?;
             }
-            inserter.flush().await?;
+            self.text_index.index_document(
+                *uuid,
+                room_properties
+                    .searchable_text()
+                    .into_iter()
+                    .chain(names.iter().map(String::as_str)),
+            );
+        }
+        for room_name in &changes.room_name_list {
+            inserter
+                .push(utils::indradb::BulkInsertItem::Vertex(
+                    utils::indradb::Vertex::with_id(
+                        room_name.uuid,
+                        self.identifiers.room_name_change_type,
+                    ),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    room_name.uuid,
+                    self.identifiers.room_name_type,
+                    serde_json::Value::String(room_name.name.clone()).into(),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    room_name.uuid,
+                    self.identifiers.origin_server_ts_type,
+                    serde_json::Value::from(u64::from(room_name.origin_server_ts.0)).into(),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::Edge(
+                    utils::indradb::Edge::new(
+                        room_name.uuid,
+                        self.identifiers.name_change_of_type,
+                        room_name.room_uuid,
+                    ),
+                ))
+                .await?;
+        }
+        for ((space_uuid, child_uuid), child) in &changes.space_children {
+            inserter
+                .push(utils::indradb::BulkInsertItem::Edge(
+                    utils::indradb::Edge::new(
+                        *space_uuid,
+                        self.identifiers.space_contains_type,
+                        *child_uuid,
+                    ),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::EdgeProperty(
+                    utils::indradb::Edge::new(
+                        *space_uuid,
+                        self.identifiers.space_contains_type,
+                        *child_uuid,
+                    ),
+                    self.identifiers.order_type,
+                    child
+                        .order
+                        .clone()
+                        .map_or(serde_json::Value::Null, serde_json::Value::String)
+                        .into(),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::EdgeProperty(
+                    utils::indradb::Edge::new(
+                        *space_uuid,
+                        self.identifiers.space_contains_type,
+                        *child_uuid,
+                    ),
+                    self.identifiers.suggested_type,
+                    serde_json::Value::Bool(child.suggested).into(),
+                ))
+                .await?;
+        }
+        for user in changes.user_list.values() {
+            inserter
+                .push(utils::indradb::BulkInsertItem::Vertex(
+                    utils::indradb::Vertex::with_id(user.uuid, self.identifiers.user_type),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    user.uuid,
+                    self.identifiers.user_id_type,
+                    serde_json::Value::String(user.user_id.to_string()).into(),
+                ))
+                .await?;
+            // Users only seen as senders or mentions keep whatever profile is stored
+            let Some(user_properties) = &user.user_properties else {
+                if !self.text_index.contains(user.uuid) {
+                    self.text_index
+                        .index_document(user.uuid, user.searchable_text());
+                }
+                continue;
+            };
+            // Write nulls for missing values as well so a removed display name or avatar is
+            // cleared instead of sticking around.
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    user.uuid,
+                    self.identifiers.user_display_name_type,
+                    user_properties
+                        .display_name
+                        .clone()
+                        .map_or(serde_json::Value::Null, serde_json::Value::String)
+                        .into(),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    user.uuid,
+                    self.identifiers.user_avatar_url_type,
+                    user_properties
+                        .avatar_url
+                        .clone()
+                        .map_or(serde_json::Value::Null, serde_json::Value::String)
+                        .into(),
+                ))
+                .await?;
+            self.text_index
+                .index_document(user.uuid, user.searchable_text());
+        }
+        for ((user_uuid, room_uuid), membership) in &changes.memberships {
+            inserter
+                .push(utils::indradb::BulkInsertItem::Edge(
+                    utils::indradb::Edge::new(
+                        *user_uuid,
+                        self.identifiers.member_of_type,
+                        *room_uuid,
+                    ),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::EdgeProperty(
+                    utils::indradb::Edge::new(
+                        *user_uuid,
+                        self.identifiers.member_of_type,
+                        *room_uuid,
+                    ),
+                    self.identifiers.membership_type,
+                    serde_json::Value::String(membership.state.clone()).into(),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::EdgeProperty(
+                    utils::indradb::Edge::new(
+                        *user_uuid,
+                        self.identifiers.member_of_type,
+                        *room_uuid,
+                    ),
+                    self.identifiers.joined_at_type,
+                    membership
+                        .joined_at
+                        .map_or(serde_json::Value::Null, |joined_at| {
+                            serde_json::Value::from(u64::from(joined_at.0))
+                        })
+                        .into(),
+                ))
+                .await?;
+        }
+
+        // What earlier pushes stored about the events decides how the changes apply
+        let mut event_uuids: BTreeSet<EventUuid> = changes
+            .message_list
+            .keys()
+            .chain(changes.edits.keys())
+            .copied()
+            .collect();
+        let edited_at: HashMap<EventUuid, u64> = self
+            .vertex_property(
+                event_uuids.iter().copied().collect(),
+                self.identifiers.edited_at_type,
+            )
+            .await?
+            .into_iter()
+            .filter_map(|(uuid, edited_at)| Some((uuid, edited_at.as_u64()?)))
+            .collect();
+        event_uuids.extend(
+            changes
+                .revision_list
+                .iter()
+                .map(|revision| revision.event_id.as_str())
+                .chain(changes.reactions.keys().map(|event_id| event_id.as_str()))
+                .map(ids::event_uuid),
+        );
+        let redacted: HashSet<Uuid> = self
+            .vertex_property(
+                event_uuids.into_iter().collect(),
+                self.identifiers.redacted_type,
+            )
+            .await?
+            .into_iter()
+            .filter(|(_, redacted)| redacted.as_bool() == Some(true))
+            .map(|(uuid, _)| uuid)
+            .collect();
+        let stored_types = self
+            .vertex_types(
+                changes
+                    .edits
+                    .keys()
+                    .chain(changes.placeholders.keys())
+                    .copied()
+                    .collect(),
+            )
+            .await?;
+
+        // Only the sender of an event may edit it. Edits arrive out of order, especially while
+        // backfilling, so the newest one of them replaces the content.
+        let original_senders = self.original_senders(&changes).await?;
+        let edits: BTreeMap<EventUuid, &UUIDEditMapType> = changes
+            .edits
+            .iter()
+            .filter(|(original_uuid, _)| !redacted.contains(*original_uuid))
+            .filter_map(|(original_uuid, edits)| {
+                let original_sender = original_senders.get(original_uuid)?;
+                let newest = edits
+                    .iter()
+                    .filter(|edit| {
+                        edit.sender.as_str() == original_sender
+                            && !redacted.contains(&ids::event_uuid(edit.event_id.as_str()))
+                    })
+                    .max_by_key(|edit| edit.origin_server_ts)?;
+                let outdated = edited_at
+                    .get(original_uuid)
+                    .is_some_and(|edited_at| *edited_at >= u64::from(newest.origin_server_ts.0));
+                (!outdated).then_some((*original_uuid, newest))
+            })
+            .collect();
+        let stored_edited: Vec<EventUuid> = edits
+            .keys()
+            .copied()
+            .filter(|uuid| {
+                !changes.message_list.contains_key(uuid) && stored_types.contains_key(uuid)
+            })
+            .collect();
+        self.copy_original_revisions(
+            stored_edited
+                .iter()
+                .copied()
+                .filter(|uuid| {
+                    !edited_at.contains_key(uuid)
+                        && stored_types.get(uuid) != Some(&self.identifiers.placeholder_event_type)
+                })
+                .collect(),
+            inserter,
+        )
+        .await?;
+        // The edit brings its own links and mentions
+        for uuid in &stored_edited {
+            for edge_type in [
+                self.identifiers.mentions_link_type,
+                self.identifiers.mentions_user_type,
+                self.identifiers.mentions_room_type,
+                self.identifiers.links_to_event_type,
+            ] {
+                self.indexer_client
+                    .delete(SpecificVertexQuery::single(*uuid).outbound()?.t(edge_type))
+                    .await?;
+            }
         }
+
+        let reaction_update = self.push_reactions(&changes, &redacted, inserter).await?;
+        let mut reaction_keys: HashMap<EventUuid, Vec<String>> = self
+            .vertex_property(
+                changes
+                    .message_list
+                    .keys()
+                    .chain(edits.keys())
+                    .copied()
+                    .filter(|uuid| !reaction_update.keys.contains_key(uuid))
+                    .collect(),
+                self.identifiers.reaction_keys_type,
+            )
+            .await?
+            .into_iter()
+            .map(|(uuid, keys)| {
+                let keys = keys
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|key| key.as_str().map(ToString::to_string))
+                    .collect();
+                (uuid, keys)
+            })
+            .collect();
+        reaction_keys.extend(reaction_update.keys);
+
+        for UUIDEventMapType {
+            event_id,
+            uuid,
+            event_type,
+            event_properties,
+            mentions,
+            origin_server_ts,
+            sender,
+        } in changes.message_list.values()
+        {
+            inserter
+                .push(utils::indradb::BulkInsertItem::Vertex(
+                    utils::indradb::Vertex::with_id(*uuid, *event_type),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    *uuid,
+                    self.identifiers.event_id_type,
+                    serde_json::Value::String(event_id.to_string()).into(),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    *uuid,
+                    self.identifiers.origin_server_ts_type,
+                    serde_json::Value::from(u64::from(origin_server_ts.0)).into(),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    *uuid,
+                    self.identifiers.sender_type,
+                    serde_json::Value::String(sender.to_string()).into(),
+                ))
+                .await?;
+            if redacted.contains(uuid) || matches!(event_properties, EventProperties::Redacted) {
+                for event_property in EventProperties::Redacted.as_vec(*uuid)? {
+                    inserter.push(event_property).await?;
+                }
+                self.text_index.remove_document(*uuid);
+                continue;
+            }
+            // An edited event holds the content of the newest edit, its own content is only
+            // kept as a revision
+            if edited_at.contains_key(uuid) || edits.contains_key(uuid) {
+                let original = UUIDRevisionMapType {
+                    uuid: ids::revision_uuid(event_id.as_str()),
+                    event_id: event_id.clone(),
+                    revision_of: *uuid,
+                    event_properties: event_properties.clone(),
+                    origin_server_ts: *origin_server_ts,
+                    sender: sender.clone(),
+                };
+                for item in self.revision_items(&original)? {
+                    inserter.push(item).await?;
+                }
+                continue;
+            }
+            for item in self.content_items(*uuid, event_properties, mentions)? {
+                inserter.push(item).await?;
+            }
+            let keys = reaction_keys.get(uuid).into_iter().flatten();
+            self.text_index.index_document(
+                *uuid,
+                event_properties
+                    .searchable_text()
+                    .into_iter()
+                    .chain(keys.map(String::as_str)),
+            );
+        }
+        for (uuid, edit) in &edits {
+            for item in self.content_items(*uuid, &edit.event_properties, &edit.mentions)? {
+                inserter.push(item).await?;
+            }
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    *uuid,
+                    self.identifiers.edited_at_type,
+                    serde_json::Value::from(u64::from(edit.origin_server_ts.0)).into(),
+                ))
+                .await?;
+            let keys = reaction_keys.get(uuid).into_iter().flatten();
+            self.text_index.index_document(
+                *uuid,
+                edit.event_properties
+                    .searchable_text()
+                    .into_iter()
+                    .chain(keys.map(String::as_str)),
+            );
+        }
+
+        for (event_uuid, room_uuid) in &changes.room_event_links {
+            inserter
+                .push(utils::indradb::BulkInsertItem::Edge(
+                    utils::indradb::Edge::new(
+                        *event_uuid,
+                        self.identifiers.event_in_room_type,
+                        *room_uuid,
+                    ),
+                ))
+                .await?;
+        }
+        for (event_uuid, user_uuid) in &changes.sender_links {
+            inserter
+                .push(utils::indradb::BulkInsertItem::Edge(
+                    utils::indradb::Edge::new(
+                        *event_uuid,
+                        self.identifiers.sent_by_type,
+                        *user_uuid,
+                    ),
+                ))
+                .await?;
+        }
+        for (event_uuid, event_id) in &changes.placeholders {
+            // Placeholders share the uuid of their event, which might have been stored by an
+            // earlier push. Writing the placeholder would turn it back into one.
+            if stored_types.contains_key(event_uuid) {
+                continue;
+            }
+            inserter
+                .push(utils::indradb::BulkInsertItem::Vertex(
+                    utils::indradb::Vertex::with_id(
+                        *event_uuid,
+                        self.identifiers.placeholder_event_type,
+                    ),
+                ))
+                .await?;
+            inserter
+                .push(utils::indradb::BulkInsertItem::VertexProperty(
+                    *event_uuid,
+                    self.identifiers.event_id_type,
+                    serde_json::Value::String(event_id.to_string()).into(),
+                ))
+                .await?;
+        }
+        for (event_uuid, reply_uuid) in &changes.reply_links {
+            inserter
+                .push(utils::indradb::BulkInsertItem::Edge(
+                    utils::indradb::Edge::new(
+                        *event_uuid,
+                        self.identifiers.reply_to_type,
+                        *reply_uuid,
+                    ),
+                ))
+                .await?;
+        }
+        for (event_uuid, thread_uuid) in &changes.thread_links {
+            inserter
+                .push(utils::indradb::BulkInsertItem::Edge(
+                    utils::indradb::Edge::new(
+                        *event_uuid,
+                        self.identifiers.in_thread_type,
+                        *thread_uuid,
+                    ),
+                ))
+                .await?;
+        }
+        for revision in &changes.revision_list {
+            // Revisions of edits by anyone but the original sender would keep forged text around
+            let by_original_sender = original_senders
+                .get(&revision.revision_of)
+                .is_some_and(|original_sender| revision.sender.as_str() == original_sender);
+            if !by_original_sender
+                || redacted.contains(&revision.revision_of)
+                || redacted.contains(&ids::event_uuid(revision.event_id.as_str()))
+            {
+                continue;
+            }
+            // Revisions are deliberately left out of the text index so only the current text of
+            // an event matches.
+            for item in self.revision_items(revision)? {
+                inserter.push(item).await?;
+            }
+        }
+        inserter.flush().await?;
+        // Deletions go last so nothing we just pushed brings the content back
+        self.apply_redactions(changes.redactions.iter()).await?;
+        for (user_uuid, event_uuid) in reaction_update.removed {
+            self.indexer_client
+                .delete(SpecificEdgeQuery::single(utils::indradb::Edge::new(
+                    user_uuid,
+                    self.identifiers.reacted_with_type,
+                    event_uuid,
+                )))
+                .await?;
+        }
+        for (space_uuid, child_uuid) in &changes.removed_space_children {
+            self.indexer_client
+                .delete(SpecificEdgeQuery::single(utils::indradb::Edge::new(
+                    *space_uuid,
+                    self.identifiers.space_contains_type,
+                    *child_uuid,
+                )))
+                .await?;
+        }
+        // Only persist the text index once the graph writes it refers to went out
+        self.text_index.save()?;
+        Ok(())
+    }
+
+    /// Requests the next page of the room currently being backfilled, resuming from the stored
+    /// checkpoints. Returns `None` once all joined rooms are backfilled.
+    fn next_backfill_page(&mut self) -> Option<PageRequest> {
+        let queue = self.backfill_queue.as_mut()?;
+        while let Some(room_id) = queue.front() {
+            let checkpoint = self.checkpoints.get(room_id);
+            let room = match self.client.get_joined_room(room_id) {
+                Some(room) if !checkpoint.done => room,
+                _ => {
+                    queue.pop_front();
+                    continue;
+                }
+            };
+            if checkpoint.events == 0 {
+                info!("Backfilling {}", room_id);
+            }
+            // Start where the live timeline begins so nothing gets processed twice
+            let from = checkpoint
+                .from
+                .or_else(|| self.prev_batches.get(room_id).cloned());
+            return Some(Box::pin(async move {
+                let mut options = MessagesOptions::backward().from(from.as_deref());
+                options.limit = backfill::PAGE_SIZE.into();
+                let messages = room.messages(options).await;
+                (room, messages)
+            }));
+        }
+        None
+    }
+
+    /// Indexes a page of a room's history until its start or one of the limits is reached.
+    ///
+    /// Every page is pushed to the indexer before its checkpoint is saved, so an interrupted
+    /// backfill continues with the first page it did not finish.
+    async fn process_backfill_page(
+        &mut self,
+        room: &Joined,
+        messages: matrix_sdk::Result<Messages>,
+        inserter: &mut BulkInserter,
+    ) -> Result<()> {
+        let room_id = room.room_id().to_owned();
+        let messages = match messages {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Failed to backfill {}: {:?}", room_id, e);
+                self.finish_backfill(&room_id);
+                return Ok(());
+            }
+        };
+        let limits = self.backfill.unwrap_or_default();
+        let cutoff = limits.max_days.map(|days| {
+            u64::from(MilliSecondsSinceUnixEpoch::now().0)
+                .saturating_sub(days.saturating_mul(86_400_000))
+        });
+        let room_uuid = self.message_map.room_uuid_or_stub(room_id.clone());
+        let mut checkpoint = self.checkpoints.get(&room_id);
+
+        // Without events or an end token we reached the creation of the room
+        let mut done = messages.chunk.is_empty() || messages.end.is_none();
+        // Events are ordered from newest to oldest
+        for event in messages.chunk {
+            let event = event.event.cast::<AnySyncTimelineEvent>();
+            let origin_server_ts = event.get_field::<u64>("origin_server_ts").ok().flatten();
+            if cutoff.is_some_and(|cutoff| origin_server_ts.is_some_and(|ts| ts < cutoff)) {
+                done = true;
+                break;
+            }
+            self.process_timeline_event(&room_id, room_uuid, &event)
+                .await;
+            checkpoint.events += 1;
+            if limits
+                .max_events
+                .is_some_and(|max_events| checkpoint.events >= max_events)
+            {
+                done = true;
+                break;
+            }
+        }
+
+        checkpoint.done = done;
+        checkpoint.from = messages.end;
+        self.push_to_indexer(inserter).await?;
+        self.checkpoints.set(room_id.clone(), checkpoint.clone());
+        self.checkpoints.save()?;
+        if done {
+            info!(
+                "Finished backfilling {} with {} events",
+                room_id, checkpoint.events
+            );
+            self.finish_backfill(&room_id);
+        }
+        Ok(())
+    }
+
+    /// Moves on to the next room to backfill.
+    fn finish_backfill(&mut self, room_id: &RoomId) {
+        if let Some(queue) = &mut self.backfill_queue {
+            queue.retain(|queued| queued != room_id);
+        }
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    pub async fn start_processing(&mut self) -> Result<()> {
+        let mut inserter = BulkInserter::new(self.indexer_client.clone());
+
+        info!("Got bulk inserter. Starting sync");
+
+        // Resume from the last batch that made it into indradb. Everything after it is synced
+        // again, which at worst indexes a few events twice.
+        let mut settings = SyncSettings::default();
+        if let Some(next_batch) = self.sync_token.get() {
+            info!("Resuming sync from {}", next_batch);
+            settings = settings.token(next_batch);
+        }
+        let client = self.client.clone();
+        let mut sync_stream = Box::pin(client.sync_stream(settings).await);
+
+        let (shutdown_tx, mut shutdown) = watch::channel(false);
+        tokio::spawn(async move {
+            match shutdown_signal().await {
+                Ok(()) => {
+                    info!("Shutting down after the current batch");
+                    let _ = shutdown_tx.send(true);
+                }
+                Err(e) => error!("Unable to listen for shutdown signals: {:?}", e),
+            }
+        });
+
+        info!("Sync obtained. Starting to process sync stream");
+        // Backfill pages are fetched while waiting for the next sync, so live events never wait
+        // for more than a single page.
+        let mut page: Option<PageRequest> = None;
+        loop {
+            if page.is_none() {
+                page = self.next_backfill_page();
+            }
+            // A batch or page which is being processed is always finished. Only waiting for the
+            // next one gets interrupted.
+            let response = tokio::select! {
+                response = sync_stream.next() => response,
+                (room, messages) = next_page(&mut page), if page.is_some() => {
+                    page = None;
+                    self.process_backfill_page(&room, messages, &mut inserter).await?;
+                    continue;
+                }
+                Ok(()) = shutdown.changed() => break,
+            };
+            let response = match response {
+                Some(Ok(response)) => response,
+                // The stream retries on its own
+                Some(Err(e)) => {
+                    error!("Sync failed: {:?}", e);
+                    continue;
+                }
+                None => bail!("The sync stream ended unexpectedly"),
+            };
+
+            for (ref room_id, room) in response.rooms.join {
+                let room_properties = self.client.get_joined_room(room_id).map_or_else(
+                    RoomProperties::default,
+                    |room| RoomProperties {
+                        name: room.name(),
+                        topic: room.topic(),
+                        canonical_alias: room.canonical_alias().as_ref().map(ToString::to_string),
+                        avatar_url: room.avatar_url().as_ref().map(ToString::to_string),
+                    },
+                );
+                let room_uuid = ids::room_uuid(room_id.as_str());
+                // The former names are only kept in memory while running
+                let names = if self.message_map.is_loaded(room_uuid) {
+                    BTreeSet::new()
+                } else {
+                    self.stored_room_names(room_uuid).await?
+                };
+                self.message_map
+                    .insert_room(room_id.clone(), room_properties, names);
+                if let Some(prev_batch) = &room.timeline.prev_batch {
+                    self.prev_batches
+                        .entry(room_id.clone())
+                        .or_insert_with(|| prev_batch.clone());
+                }
+
+                for event in &room.state.events {
+                    match event.deserialize() {
+                        Ok(event) => {
+                            self.process_state_event(room_id, room_uuid, &event).await;
+                        }
+                        Err(e) => error!("Error deserializing state event: {}", e),
+                    }
+                }
+                for e in &room.timeline.events {
+                    self.process_timeline_event(room_id, room_uuid, &e.event)
+                        .await;
+                }
+            }
+
+            // Push to indexer after we preprocessed it
+            self.push_to_indexer(&mut inserter).await?;
+            // Only now the batch is safe to skip on the next start
+            self.sync_token.save(response.next_batch)?;
+
+            // After the first sync we know the joined rooms and where their timelines start
+            if self.backfill.is_some() && self.backfill_queue.is_none() {
+                self.backfill_queue = Some(
+                    self.client
+                        .joined_rooms()
+                        .iter()
+                        .map(|room| room.room_id().to_owned())
+                        .collect(),
+                );
+            }
+        }
+
+        inserter.shutdown().await?;
+        info!("Indexer stopped");
         Ok(())
     }
 }
+
+/// Waits for the pending backfill page, if there is one.
+async fn next_page(page: &mut Option<PageRequest>) -> (Joined, matrix_sdk::Result<Messages>) {
+    match page {
+        Some(page) => page.await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once the process got asked to stop with Ctrl-C, or SIGTERM on unix.
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<()> {
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
@@ -0,0 +1,269 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use matrix_sdk::ruma::{
+    matrix_uri::MatrixId, MatrixToUri, MatrixUri, OwnedEventId, OwnedRoomAliasId, OwnedRoomId,
+    OwnedRoomOrAliasId, OwnedUserId, RoomAliasId, UserId,
+};
+
+use crate::links;
+
+/// Users, rooms and events a message refers to.
+#[derive(Debug, Default)]
+pub struct Mentions {
+    pub users: BTreeSet<OwnedUserId>,
+    /// Room ids and aliases.
+    pub rooms: BTreeSet<OwnedRoomOrAliasId>,
+    pub events: BTreeSet<OwnedEventId>,
+    /// Whether the whole room the message was sent in got mentioned with `@room`.
+    pub room: bool,
+}
+
+impl Mentions {
+    /// Collects the intentional mentions of the `m.mentions` content field, the permalinks and
+    /// pills in the body and plain `@user:server` references.
+    ///
+    /// Plain `#alias:server` words only count if `known_alias` finds the room of the alias
+    /// locally. Anyone can write such words, so they must not make us ask the homeserver.
+    pub fn extract(
+        body: &str,
+        formatted_body: Option<&str>,
+        intentional: Option<&serde_json::Value>,
+        known_alias: impl Fn(&RoomAliasId) -> Option<OwnedRoomId>,
+    ) -> Self {
+        let mut mentions = Self::default();
+        if let Some(intentional) = intentional {
+            mentions.users.extend(
+                intentional
+                    .get("user_ids")
+                    .and_then(serde_json::Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(serde_json::Value::as_str)
+                    .filter_map(|user_id| UserId::parse(user_id).ok()),
+            );
+            mentions.room = intentional
+                .get("room")
+                .and_then(serde_json::Value::as_bool)
+                .unwrap_or_default();
+        }
+
+        for url in links::urls(body, formatted_body) {
+            let id = if let Ok(uri) = MatrixToUri::parse(&url) {
+                uri.id().clone()
+            } else if let Ok(uri) = MatrixUri::parse(&url) {
+                uri.id().clone()
+            } else {
+                continue;
+            };
+            mentions.insert(id);
+        }
+
+        for word in links::strip_reply_fallback(body).split_whitespace() {
+            let word = word
+                .trim_start_matches(['(', '<', '"', '\''])
+                .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '>']);
+            if word.starts_with('@') {
+                mentions.users.extend(UserId::parse(word).ok());
+            } else if word.starts_with('#') {
+                let room_id = RoomAliasId::parse(word)
+                    .ok()
+                    .and_then(|alias| known_alias(&alias));
+                mentions.rooms.extend(room_id.map(Into::into));
+            }
+        }
+        mentions
+    }
+
+    fn insert(&mut self, id: MatrixId) {
+        match id {
+            MatrixId::User(user_id) => {
+                self.users.insert(user_id);
+            }
+            MatrixId::Room(room_id) => {
+                self.rooms.insert(room_id.into());
+            }
+            MatrixId::RoomAlias(alias) => {
+                self.rooms.insert(alias.into());
+            }
+            // Event ids are unique across rooms, so the room part of the link is not needed
+            MatrixId::Event(_, event_id) => {
+                self.events.insert(event_id);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// How long a resolved room alias is trusted, as aliases can be moved to other rooms.
+#[allow(clippy::duration_suboptimal_units)]
+const ALIAS_TTL: Duration = Duration::from_secs(60 * 60);
+/// How many room aliases are remembered at most.
+const ALIAS_CACHE_SIZE: usize = 1_000;
+
+#[derive(Debug)]
+struct CachedAlias {
+    /// `None` if the alias could not be resolved.
+    room_id: Option<OwnedRoomId>,
+    resolved_at: Instant,
+}
+
+/// Room aliases resolved through the homeserver. Any room member can make us resolve aliases, so
+/// entries expire and their number is bounded.
+#[derive(Debug, Default)]
+pub struct AliasCache {
+    aliases: HashMap<OwnedRoomAliasId, CachedAlias>,
+}
+
+impl AliasCache {
+    /// The cached resolution of an alias. `None` if it has to be resolved again.
+    pub fn get(&self, alias: &RoomAliasId) -> Option<&Option<OwnedRoomId>> {
+        self.aliases
+            .get(alias)
+            .filter(|cached| cached.resolved_at.elapsed() < ALIAS_TTL)
+            .map(|cached| &cached.room_id)
+    }
+
+    pub fn insert(&mut self, alias: OwnedRoomAliasId, room_id: Option<OwnedRoomId>) {
+        if self.aliases.len() >= ALIAS_CACHE_SIZE {
+            self.aliases
+                .retain(|_, cached| cached.resolved_at.elapsed() < ALIAS_TTL);
+        }
+        if self.aliases.len() >= ALIAS_CACHE_SIZE {
+            let oldest = self
+                .aliases
+                .iter()
+                .min_by_key(|(_, cached)| cached.resolved_at)
+                .map(|(alias, _)| alias.clone());
+            if let Some(oldest) = oldest {
+                self.aliases.remove(&oldest);
+            }
+        }
+        self.aliases.insert(
+            alias,
+            CachedAlias {
+                room_id,
+                resolved_at: Instant::now(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::{event_id, room_alias_id, room_id, user_id};
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn intentional_mentions() {
+        let intentional = json!({
+            "user_ids": ["@alice:example.org", "not a user id"],
+            "room": true,
+        });
+        let mentions = Mentions::extract("hello", None, Some(&intentional), |_| None);
+        assert_eq!(
+            mentions.users,
+            BTreeSet::from([user_id!("@alice:example.org").to_owned()])
+        );
+        assert!(mentions.room);
+        assert!(mentions.rooms.is_empty());
+        assert!(mentions.events.is_empty());
+    }
+
+    #[test]
+    fn permalinks_and_pills() {
+        let mentions = Mentions::extract(
+            "Bob: see https://matrix.to/#/!room:example.org/$event:example.org?via=example.org",
+            Some(
+                "<a href=\"https://matrix.to/#/@bob:example.org\">Bob</a>: see \
+                 <a href=\"https://matrix.to/#/#space:example.org\">the space</a> and \
+                 <a href=\"matrix:u/carol:example.org\">Carol</a>",
+            ),
+            None,
+            |_| None,
+        );
+        assert_eq!(
+            mentions.users,
+            BTreeSet::from([
+                user_id!("@bob:example.org").to_owned(),
+                user_id!("@carol:example.org").to_owned(),
+            ])
+        );
+        assert_eq!(
+            mentions.rooms,
+            BTreeSet::from([OwnedRoomOrAliasId::from(
+                room_alias_id!("#space:example.org").to_owned()
+            )])
+        );
+        assert_eq!(
+            mentions.events,
+            BTreeSet::from([event_id!("$event:example.org").to_owned()])
+        );
+        assert!(!mentions.room);
+    }
+
+    #[test]
+    fn plain_user_ids() {
+        let mentions = Mentions::extract(
+            "ping @alice:example.org, (@bob:example.org) and @nobody",
+            None,
+            None,
+            |_| None,
+        );
+        assert_eq!(
+            mentions.users,
+            BTreeSet::from([
+                user_id!("@alice:example.org").to_owned(),
+                user_id!("@bob:example.org").to_owned(),
+            ])
+        );
+    }
+
+    #[test]
+    fn plain_aliases_only_when_known() {
+        let known_alias = |alias: &RoomAliasId| {
+            (alias == "#room:example.org").then(|| room_id!("!room:example.org").to_owned())
+        };
+        let mentions = Mentions::extract(
+            "join #room:example.org, #unknown:example.org or !other:example.org",
+            None,
+            None,
+            known_alias,
+        );
+        assert_eq!(
+            mentions.rooms,
+            BTreeSet::from([OwnedRoomOrAliasId::from(
+                room_id!("!room:example.org").to_owned()
+            )])
+        );
+
+        let mentions =
+            Mentions::extract("https://matrix.to/#/!room:example.org", None, None, |_| {
+                None
+            });
+        assert_eq!(
+            mentions.rooms,
+            BTreeSet::from([OwnedRoomOrAliasId::from(
+                room_id!("!room:example.org").to_owned()
+            )])
+        );
+    }
+
+    #[test]
+    fn reply_fallbacks_are_ignored() {
+        let mentions = Mentions::extract(
+            "> <@alice:example.org> hi @bob:example.org\n\nhello",
+            Some(
+                "<mx-reply><a href=\"https://matrix.to/#/@alice:example.org\">Alice</a></mx-reply>\
+                 hello",
+            ),
+            None,
+            |_| None,
+        );
+        assert!(mentions.users.is_empty());
+    }
+}
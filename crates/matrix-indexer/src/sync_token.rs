@@ -0,0 +1,43 @@
+use std::{fs::File, io::BufReader, path::PathBuf};
+
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+
+/// Where the sync token is stored.
+pub const SYNC_TOKEN_PATH: &str = "./sync_token.json";
+
+/// The `next_batch` token of the last sync whose changes reached indradb.
+///
+/// The matrix-sdk store keeps its own token, but that one is saved as soon as a response arrives.
+/// Resuming from it after a crash would skip whatever was not pushed yet, so the sync loop always
+/// starts from this one instead.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncToken {
+    next_batch: Option<String>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl SyncToken {
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut token = if path.exists() {
+            let file = File::open(&path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            SyncToken::default()
+        };
+        token.path = path;
+        Ok(token)
+    }
+
+    pub fn get(&self) -> Option<&str> {
+        self.next_batch.as_deref()
+    }
+
+    /// Remembers `next_batch` and writes it to disk.
+    pub fn save(&mut self, next_batch: String) -> Result<()> {
+        self.next_batch = Some(next_batch);
+        utils::write_json_atomic(&self.path, self)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use uuid::Uuid;
+
+/// Namespace of all vertex uuids.
+///
+/// Vertices get v5 uuids derived from their matrix identifiers. Indexing the same room, event or
+/// user again therefore writes to the same vertex and every component can compute the uuid of a
+/// vertex without looking it up.
+const NAMESPACE: Uuid = Uuid::from_u128(0x88d7_d251_69d3_431b_9791_38e7_c94a_15f2);
+
+/// Derives the uuid of a vertex from its kind and a key which is unique within that kind.
+fn vertex_uuid(kind: &str, key: &str) -> Uuid {
+    Uuid::new_v5(&NAMESPACE, format!("{kind}:{key}").as_bytes())
+}
+
+pub fn room_uuid(room_id: &str) -> Uuid {
+    vertex_uuid("room", room_id)
+}
+
+/// The uuid of an event, which is shared with the placeholder written before we saw the event.
+pub fn event_uuid(event_id: &str) -> Uuid {
+    vertex_uuid("event", event_id)
+}
+
+pub fn user_uuid(user_id: &str) -> Uuid {
+    vertex_uuid("user", user_id)
+}
+
+/// The uuid of a revision by the event which carried its content, which is either an edit or
+/// the original event.
+pub fn revision_uuid(event_id: &str) -> Uuid {
+    vertex_uuid("revision", event_id)
+}
+
+/// The uuid of a former room name by the room vertex and the time the name was set.
+pub fn room_name_change_uuid(room_uuid: Uuid, origin_server_ts: u64) -> Uuid {
+    vertex_uuid("room_name_change", &format!("{room_uuid}:{origin_server_ts}"))
+}
+
+/// The uuid of a link by its normalized URL.
+pub fn link_uuid(url: &str) -> Uuid {
+    vertex_uuid("link", url)
+}
@@ -15,16 +15,25 @@
     clippy::suboptimal_flops,
     clippy::fn_to_numeric_cast_any,
     clippy::if_then_some_else_none,
-    clippy::imprecise_flops,
     clippy::lossy_float_literal,
     clippy::panic_in_result_fn,
     clippy::clone_on_ref_ptr
 )]
-#![allow(clippy::missing_panics_doc, clippy::panic_in_result_fn)]
+#![allow(
+    clippy::missing_panics_doc,
+    clippy::missing_errors_doc,
+    clippy::must_use_candidate,
+    clippy::panic_in_result_fn
+)]
 // I am lazy. Dont blame me!
 #![allow(missing_docs)]
 
-use std::{collections::BTreeSet, fs::OpenOptions, io::Read, path::PathBuf};
+use std::{
+    collections::BTreeSet,
+    fs::OpenOptions,
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
 
 pub use indradb;
 pub use indradb_proto;
@@ -32,6 +41,9 @@ use serde::{Deserialize, Serialize};
 use tokio::time::{sleep, Duration};
 use tracing::instrument;
 
+pub mod ids;
+pub mod text_index;
+
 pub async fn get_client(
     endpoint: String,
 ) -> Result<indradb_proto::Client, indradb_proto::ClientError> {
@@ -72,6 +84,15 @@ fn get_identifier_config() -> PathBuf {
     }
 }
 
+pub fn get_text_index_path() -> PathBuf {
+    let data_path = dirs::data_dir();
+    if let Some(data_path) = data_path {
+        data_path.join("knowledge-search/text_index.json")
+    } else {
+        panic!("System not supported.")
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct IdentifierConfig {
     identifiers: BTreeSet<String>,
@@ -81,6 +102,7 @@ pub fn get_identifier() -> Result<BTreeSet<String>, std::io::Error> {
     let identifier_file = OpenOptions::new()
         .read(true)
         .create(true)
+        .truncate(false)
         .open(get_identifier_config())?;
     let identifier_config: IdentifierConfig = serde_json::from_reader(identifier_file)?;
     Ok(identifier_config.identifiers)
@@ -117,3 +139,22 @@ pub fn add_identifiers(identifiers: &mut BTreeSet<String>) -> Result<(), std::io
 
     Ok(())
 }
+
+/// Writes `value` as JSON to `path` without ever leaving a partially written file behind.
+///
+/// The JSON goes to a temporary file next to `path` first, which is synced and then renamed over
+/// `path`. Readers and restarts after a crash see either the old or the new contents.
+pub fn write_json_atomic<T: Serialize>(path: &Path, value: &T) -> Result<(), std::io::Error> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(&tmp_path)?;
+    let mut writer = BufWriter::new(tmp_file);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    std::fs::rename(tmp_path, path)
+}
@@ -0,0 +1,676 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument};
+use uuid::Uuid;
+
+/// BM25 term frequency saturation.
+const K1: f32 = 1.2;
+/// BM25 document length normalization.
+const B: f32 = 0.75;
+
+/// Whether the character is part of an emoji or a similar symbol rather than punctuation.
+///
+/// Joiners and variation selectors count as well so sequences like skin tones stay together.
+fn is_symbol(c: char) -> bool {
+    let punctuation = matches!(
+        c,
+        '\u{80}'..='\u{bf}'
+            | '\u{2000}'..='\u{200c}'
+            | '\u{200e}'..='\u{206f}'
+            | '\u{3000}'..='\u{303f}'
+    );
+    !c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace() && !punctuation
+}
+
+/// Splits text into lowercased alphanumeric terms.
+///
+/// Runs of emoji form terms of their own so reactions and emoji in messages can be searched for.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    let mut terms = Vec::new();
+    let mut term = String::new();
+    let mut in_symbols = false;
+    // Emoji are often sent with and without the presentation selector
+    for c in text.chars().filter(|&c| c != '\u{fe0f}') {
+        let symbol = is_symbol(c);
+        let part_of_term = symbol || c.is_alphanumeric();
+        // A term ends at anything else and where letters and emoji meet
+        if !term.is_empty() && (!part_of_term || symbol != in_symbols) {
+            terms.push(std::mem::take(&mut term));
+        }
+        if part_of_term {
+            in_symbols = symbol;
+            term.extend(c.to_lowercase());
+        }
+    }
+    if !term.is_empty() {
+        terms.push(term);
+    }
+    terms.into_iter()
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Document {
+    length: u32,
+    terms: Vec<String>,
+}
+
+/// How many log entries are always allowed before the log is folded into a new snapshot. Above
+/// that the log may grow as long as the index has documents.
+const MIN_COMPACTION_ENTRIES: usize = 10_000;
+
+/// A change of a single document, appended to the log of the index.
+#[derive(Debug, Serialize, Deserialize)]
+enum LogEntry {
+    Index {
+        uuid: Uuid,
+        frequencies: BTreeMap<String, u32>,
+    },
+    Remove {
+        uuid: Uuid,
+    },
+}
+
+/// A full-text inverted index over vertices scored using BM25.
+///
+/// Each vertex is a single document made up of all text fields it was indexed with.
+/// The index is shared between the indexer (writer) and the search server (reader) through
+/// files on disk: a snapshot and a log of the changes made since the snapshot was written.
+/// Saving only appends to the log, and readers only read what was appended since they last
+/// looked. Once the log outgrows the index it gets folded into a new snapshot.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TextIndex {
+    /// term -> vertex -> term frequency
+    postings: HashMap<String, BTreeMap<Uuid, u32>>,
+    documents: HashMap<Uuid, Document>,
+    total_length: u64,
+    /// Increased with every snapshot. Each snapshot has a log of its own.
+    #[serde(default)]
+    generation: u64,
+
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    snapshot_modified: Option<SystemTime>,
+    /// How far the log of this generation was read or written.
+    #[serde(skip)]
+    log_offset: u64,
+    #[serde(skip)]
+    log_entries: usize,
+    /// Changes which were not saved yet.
+    #[serde(skip)]
+    pending: Vec<LogEntry>,
+}
+
+/// Where a reader is in the files of an index. Used to read updates without holding on to the
+/// index itself.
+#[derive(Debug, Clone)]
+pub struct Position {
+    path: PathBuf,
+    snapshot_modified: Option<SystemTime>,
+    generation: u64,
+    log_offset: u64,
+}
+
+/// Changes written by another process, see [`Position::read_update`].
+#[derive(Debug)]
+pub struct Update(UpdateKind);
+
+#[derive(Debug)]
+enum UpdateKind {
+    Unchanged,
+    Appended {
+        entries: Vec<LogEntry>,
+        log_offset: u64,
+    },
+    Replaced(Box<TextIndex>),
+}
+
+impl Position {
+    /// Reads what changed on disk since this position. This is blocking file I/O.
+    pub fn read_update(&self) -> Result<Update, std::io::Error> {
+        if modified_at(&self.path) != self.snapshot_modified {
+            debug!("Reloading text index from {:?}", self.path);
+            let index = TextIndex::load(self.path.clone())?;
+            return Ok(Update(UpdateKind::Replaced(Box::new(index))));
+        }
+        let (entries, log_offset) =
+            read_log(&log_path(&self.path, self.generation), self.log_offset)?;
+        if entries.is_empty() {
+            return Ok(Update(UpdateKind::Unchanged));
+        }
+        Ok(Update(UpdateKind::Appended {
+            entries,
+            log_offset,
+        }))
+    }
+}
+
+impl TextIndex {
+    /// Loads the index from `path` or creates an empty one if the file does not exist yet.
+    /// Changes logged since the snapshot was written are applied as well.
+    #[instrument]
+    pub fn load(path: PathBuf) -> Result<Self, std::io::Error> {
+        let mut index = if path.exists() {
+            let file = File::open(&path)?;
+            serde_json::from_reader(BufReader::new(file))?
+        } else {
+            TextIndex::default()
+        };
+        index.snapshot_modified = modified_at(&path);
+        let (entries, log_offset) = read_log(&log_path(&path, index.generation), 0)?;
+        index.path = path;
+        index.apply_entries(&entries, log_offset);
+        Ok(index)
+    }
+
+    /// Where this index is in its files, to check for updates with [`Position::read_update`].
+    pub fn position(&self) -> Position {
+        Position {
+            path: self.path.clone(),
+            snapshot_modified: self.snapshot_modified,
+            generation: self.generation,
+            log_offset: self.log_offset,
+        }
+    }
+
+    /// Applies an update read from the position of this index.
+    ///
+    /// Returns true if anything changed.
+    pub fn apply_update(&mut self, update: Update) -> bool {
+        match update.0 {
+            UpdateKind::Unchanged => false,
+            UpdateKind::Appended {
+                entries,
+                log_offset,
+            } => {
+                self.apply_entries(&entries, log_offset);
+                true
+            }
+            UpdateKind::Replaced(index) => {
+                *self = *index;
+                true
+            }
+        }
+    }
+
+    fn apply_entries(&mut self, entries: &[LogEntry], log_offset: u64) {
+        for entry in entries {
+            match entry {
+                LogEntry::Index { uuid, frequencies } => self.set_document(*uuid, frequencies),
+                LogEntry::Remove { uuid } => {
+                    self.unset_document(*uuid);
+                }
+            }
+        }
+        self.log_entries += entries.len();
+        self.log_offset = log_offset;
+    }
+
+    /// Persists the changes made since the last save.
+    ///
+    /// Usually they are appended to the log. A new snapshot is only written once the log has more
+    /// entries than the index has documents, which keeps the work per change constant.
+    pub fn save(&mut self) -> Result<(), std::io::Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if let Some(prefix) = self.path.parent() {
+            std::fs::create_dir_all(prefix)?;
+        }
+        if self.log_entries + self.pending.len() > self.documents.len().max(MIN_COMPACTION_ENTRIES)
+        {
+            return self.compact();
+        }
+
+        let mut buf = Vec::new();
+        for entry in &self.pending {
+            serde_json::to_writer(&mut buf, entry)?;
+            buf.push(b'\n');
+        }
+        let mut log = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(log_path(&self.path, self.generation))?;
+        // Cut off whatever a crash in the middle of an earlier save left behind
+        log.set_len(self.log_offset)?;
+        log.seek(SeekFrom::Start(self.log_offset))?;
+        log.write_all(&buf)?;
+        log.sync_data()?;
+
+        self.log_offset += buf.len() as u64;
+        self.log_entries += self.pending.len();
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Writes a snapshot of the whole index and starts a new, empty log.
+    fn compact(&mut self) -> Result<(), std::io::Error> {
+        debug!("Writing a text index snapshot to {:?}", self.path);
+        let old_log = log_path(&self.path, self.generation);
+        self.generation += 1;
+
+        crate::write_json_atomic(&self.path, self)?;
+        match std::fs::remove_file(old_log) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+
+        self.snapshot_modified = modified_at(&self.path);
+        self.log_offset = 0;
+        self.log_entries = 0;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Number of indexed documents.
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    pub fn contains(&self, uuid: Uuid) -> bool {
+        self.documents.contains_key(&uuid)
+    }
+
+    /// Indexes the given text fields for a vertex. Any earlier version of the document is replaced.
+    pub fn index_document<'a>(&mut self, uuid: Uuid, fields: impl IntoIterator<Item = &'a str>) {
+        let mut frequencies: BTreeMap<String, u32> = BTreeMap::new();
+        for term in fields.into_iter().flat_map(tokenize) {
+            *frequencies.entry(term).or_default() += 1;
+        }
+        self.insert_document(uuid, frequencies);
+    }
+
+    /// Swaps some text fields of an indexed vertex without having to know the rest of its
+    /// document. Does nothing if the vertex is not indexed.
+    pub fn replace_fields<'a>(
+        &mut self,
+        uuid: Uuid,
+        removed: impl IntoIterator<Item = &'a str>,
+        added: impl IntoIterator<Item = &'a str>,
+    ) {
+        let Some(document) = self.documents.get(&uuid) else {
+            return;
+        };
+        let mut frequencies: BTreeMap<String, u32> = document
+            .terms
+            .iter()
+            .filter_map(|term| Some((term.clone(), *self.postings.get(term)?.get(&uuid)?)))
+            .collect();
+        for term in removed.into_iter().flat_map(tokenize) {
+            if let Some(frequency) = frequencies.get_mut(&term) {
+                *frequency = frequency.saturating_sub(1);
+            }
+        }
+        frequencies.retain(|_, frequency| *frequency > 0);
+        for term in added.into_iter().flat_map(tokenize) {
+            *frequencies.entry(term).or_default() += 1;
+        }
+        self.insert_document(uuid, frequencies);
+    }
+
+    /// Replaces the document of a vertex with the given term frequencies.
+    fn insert_document(&mut self, uuid: Uuid, frequencies: BTreeMap<String, u32>) {
+        if let Some(document) = self.documents.get(&uuid) {
+            let unchanged = document.length == frequencies.values().sum::<u32>()
+                && document.terms.len() == frequencies.len()
+                && document.terms.iter().all(|term| {
+                    self.postings
+                        .get(term)
+                        .and_then(|postings| postings.get(&uuid))
+                        == frequencies.get(term)
+                });
+            if unchanged {
+                return;
+            }
+        }
+
+        if frequencies.is_empty() {
+            self.remove_document(uuid);
+            return;
+        }
+        self.set_document(uuid, &frequencies);
+        self.pending.push(LogEntry::Index { uuid, frequencies });
+    }
+
+    /// Removes a vertex from the index.
+    pub fn remove_document(&mut self, uuid: Uuid) {
+        if self.unset_document(uuid) {
+            self.pending.push(LogEntry::Remove { uuid });
+        }
+    }
+
+    fn set_document(&mut self, uuid: Uuid, frequencies: &BTreeMap<String, u32>) {
+        self.unset_document(uuid);
+        let length = frequencies.values().sum();
+        if length == 0 {
+            return;
+        }
+
+        for (term, frequency) in frequencies {
+            self.postings
+                .entry(term.clone())
+                .or_default()
+                .insert(uuid, *frequency);
+        }
+        let terms = frequencies.keys().cloned().collect();
+        self.documents.insert(uuid, Document { length, terms });
+        self.total_length += u64::from(length);
+    }
+
+    /// Returns false if the vertex was not indexed.
+    fn unset_document(&mut self, uuid: Uuid) -> bool {
+        let Some(document) = self.documents.remove(&uuid) else {
+            return false;
+        };
+        for term in &document.terms {
+            if let Some(postings) = self.postings.get_mut(term) {
+                postings.remove(&uuid);
+                if postings.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+        self.total_length -= u64::from(document.length);
+        true
+    }
+
+    /// Scores all documents containing at least one query term using BM25.
+    ///
+    /// Returns at most `limit` hits ordered by descending score.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<(Uuid, f32)> {
+        let mut scores: HashMap<Uuid, f32> = HashMap::new();
+        for term in query_terms(query) {
+            let Some(postings) = self.postings.get(&term) else {
+                continue;
+            };
+            let idf = self.idf(postings.len());
+            for (uuid, &frequency) in postings {
+                *scores.entry(*uuid).or_default() += self.term_score(uuid, frequency, idf);
+            }
+        }
+        top_hits(scores, limit)
+    }
+
+    /// Scores only the documents containing every query term using BM25.
+    ///
+    /// The postings are intersected before scoring, so documents matching all terms are found
+    /// however many documents match just one of them. Documents `allowed` rejects are skipped
+    /// before the hits are cut off at `limit`.
+    pub fn search_all(
+        &self,
+        query: &str,
+        limit: usize,
+        allowed: impl Fn(&Uuid) -> bool,
+    ) -> Vec<(Uuid, f32)> {
+        let terms = query_terms(query);
+        let Some(mut postings) = terms
+            .iter()
+            .map(|term| self.postings.get(term))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return Vec::new();
+        };
+        // Walking the rarest term keeps the intersection cheap
+        postings.sort_by_key(|postings| postings.len());
+        let Some((rarest, rest)) = postings.split_first() else {
+            return Vec::new();
+        };
+
+        let mut scores: HashMap<Uuid, f32> = HashMap::new();
+        for uuid in rarest.keys() {
+            if !rest.iter().all(|postings| postings.contains_key(uuid)) || !allowed(uuid) {
+                continue;
+            }
+            let score = postings
+                .iter()
+                .filter_map(|postings| {
+                    let frequency = *postings.get(uuid)?;
+                    Some(self.term_score(uuid, frequency, self.idf(postings.len())))
+                })
+                .sum();
+            scores.insert(*uuid, score);
+        }
+        top_hits(scores, limit)
+    }
+
+    /// Inverse document frequency of a term found in `matching` documents.
+    #[allow(clippy::cast_precision_loss)]
+    fn idf(&self, matching: usize) -> f32 {
+        let document_count = self.documents.len() as f32;
+        let matching = matching as f32;
+        ((document_count - matching + 0.5) / (matching + 0.5)).ln_1p()
+    }
+
+    /// BM25 score of a single term occurring `frequency` times in a document.
+    #[allow(clippy::cast_precision_loss)]
+    fn term_score(&self, uuid: &Uuid, frequency: u32, idf: f32) -> f32 {
+        let average_length = self.total_length as f32 / self.documents.len() as f32;
+        let length = self.documents.get(uuid).map_or(0, |d| d.length) as f32;
+        let frequency = frequency as f32;
+        let length_ratio = B.mul_add(length / average_length, 1.0 - B);
+        let normalization = K1.mul_add(length_ratio, frequency);
+        idf * frequency * (K1 + 1.0) / normalization
+    }
+}
+
+/// The distinct terms of a query.
+fn query_terms(query: &str) -> Vec<String> {
+    let mut terms: Vec<String> = tokenize(query).collect();
+    terms.sort_unstable();
+    terms.dedup();
+    terms
+}
+
+/// The `limit` best scored hits, best first.
+fn top_hits(scores: HashMap<Uuid, f32>, limit: usize) -> Vec<(Uuid, f32)> {
+    let mut hits: Vec<(Uuid, f32)> = scores.into_iter().collect();
+    hits.sort_by(|a, b| b.1.total_cmp(&a.1));
+    hits.truncate(limit);
+    hits
+}
+
+/// The log holding the changes made after the snapshot of `generation` was written.
+fn log_path(path: &Path, generation: u64) -> PathBuf {
+    path.with_extension(format!("{generation}.log"))
+}
+
+/// Reads the complete log entries after `offset`. Returns them with the offset after the last one.
+fn read_log(path: &Path, offset: u64) -> Result<(Vec<LogEntry>, u64), std::io::Error> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((Vec::new(), offset)),
+        Err(e) => return Err(e),
+    };
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+
+    // The writer might be in the middle of appending the last line
+    let complete = buf
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |end| end + 1);
+    let entries = buf[..complete]
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(serde_json::from_slice)
+        .collect::<Result<_, _>>()?;
+    Ok((entries, offset + complete as u64))
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory for the files of one test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("text-index-{}-{name}", std::process::id()));
+        match std::fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => panic!("{e}"),
+            _ => {}
+        }
+        std::fs::create_dir_all(&dir).expect("Test directory can be created");
+        dir
+    }
+
+    fn uuids(hits: &[(Uuid, f32)]) -> Vec<Uuid> {
+        hits.iter().map(|(uuid, _)| *uuid).collect()
+    }
+
+    #[test]
+    fn tokenize_terms() {
+        let terms: Vec<String> = tokenize("Hello, World! it's 2023… ok👍🏽 ❤\u{fe0f}").collect();
+        assert_eq!(
+            terms,
+            ["hello", "world", "it", "s", "2023", "ok", "👍🏽", "❤"]
+        );
+        assert_eq!(tokenize(" -- ").count(), 0);
+    }
+
+    #[test]
+    fn bm25_order() {
+        let mut index = TextIndex::default();
+        let (often, once, other) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        index.index_document(often, ["rust rust rust"]);
+        index.index_document(once, ["rust and python"]);
+        index.index_document(other, ["python"]);
+
+        let hits = index.search("Rust", 10);
+        assert_eq!(uuids(&hits), [often, once]);
+        assert!(hits[0].1 > hits[1].1);
+        assert_eq!(uuids(&index.search("rust", 1)), [often]);
+        assert!(index.search("java", 10).is_empty());
+
+        // Documents matching more terms come first
+        assert_eq!(uuids(&index.search("python and", 10))[0], once);
+    }
+
+    #[test]
+    fn search_all_terms() {
+        let mut index = TextIndex::default();
+        let (both, rust, python) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        index.index_document(both, ["rust", "python"]);
+        index.index_document(rust, ["rust rust"]);
+        index.index_document(python, ["python"]);
+
+        assert_eq!(index.search("rust python", 10).len(), 3);
+        assert_eq!(
+            uuids(&index.search_all("rust python", 10, |_| true)),
+            [both]
+        );
+        assert_eq!(uuids(&index.search_all("python", 10, |_| true)).len(), 2);
+        assert!(index.search_all("rust java", 10, |_| true).is_empty());
+        assert!(index.search_all("", 10, |_| true).is_empty());
+
+        // Filtered documents don't take up the limit
+        assert_eq!(
+            uuids(&index.search_all("python", 1, |uuid| *uuid != python)),
+            [both]
+        );
+    }
+
+    #[test]
+    fn replace_and_remove() {
+        let mut index = TextIndex::default();
+        let uuid = Uuid::from_u128(1);
+        index.index_document(uuid, ["old title", "body text"]);
+        index.replace_fields(uuid, ["old title"], ["new title"]);
+        assert!(index.search("old", 10).is_empty());
+        assert_eq!(
+            uuids(&index.search_all("new title body", 10, |_| true)),
+            [uuid]
+        );
+
+        // Only indexed vertices are changed
+        let unknown = Uuid::from_u128(2);
+        index.replace_fields(unknown, [], ["new"]);
+        assert!(!index.contains(unknown));
+
+        index.remove_document(uuid);
+        assert!(index.is_empty());
+        assert!(index.search("title", 10).is_empty());
+        assert!(index.postings.is_empty());
+        assert_eq!(index.total_length, 0);
+    }
+
+    #[test]
+    fn partially_written_log() {
+        let path = test_dir("partial").join("index.json");
+        let mut index = TextIndex::load(path.clone()).expect("Index loads");
+        index.index_document(Uuid::from_u128(1), ["first"]);
+        index.save().expect("Index saves");
+
+        // A crash in the middle of appending the next entry
+        let log = log_path(&path, 0);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&log)
+            .expect("Log exists");
+        file.write_all(br#"{"Index":{"uuid":"#)
+            .expect("Log is writable");
+        drop(file);
+
+        let mut index = TextIndex::load(path.clone()).expect("Index loads");
+        assert_eq!(index.len(), 1);
+        assert!(index.contains(Uuid::from_u128(1)));
+
+        // The next save replaces the incomplete line
+        index.index_document(Uuid::from_u128(2), ["second"]);
+        index.save().expect("Index saves");
+        let index = TextIndex::load(path).expect("Index loads");
+        assert_eq!(index.len(), 2);
+        assert_eq!(uuids(&index.search("second", 10)), [Uuid::from_u128(2)]);
+    }
+
+    #[test]
+    fn compaction_starts_a_new_generation() {
+        let path = test_dir("compaction").join("index.json");
+        let mut writer = TextIndex::load(path.clone()).expect("Index loads");
+        let count = MIN_COMPACTION_ENTRIES as u128 + 1;
+        for i in 0..count {
+            writer.index_document(Uuid::from_u128(i), ["document"]);
+        }
+        writer.save().expect("Index saves");
+        assert_eq!(writer.generation, 0);
+        assert!(!path.exists());
+
+        let mut reader = TextIndex::load(path.clone()).expect("Index loads");
+        assert_eq!(reader.len(), writer.len());
+        assert!(!reader.apply_update(reader.position().read_update().expect("Update is readable")));
+
+        // The log now outgrows the index
+        writer.remove_document(Uuid::from_u128(0));
+        writer.save().expect("Index saves");
+        assert_eq!(writer.generation, 1);
+        assert!(path.exists());
+        assert!(!log_path(&path, 0).exists());
+
+        assert!(reader.apply_update(reader.position().read_update().expect("Update is readable")));
+        assert_eq!(reader.generation, 1);
+        assert_eq!(reader.len(), writer.len());
+        assert!(!reader.contains(Uuid::from_u128(0)));
+
+        // Later changes go to the log of the new generation
+        writer.index_document(Uuid::from_u128(0), ["again"]);
+        writer.save().expect("Index saves");
+        assert!(log_path(&path, 1).exists());
+        assert!(reader.apply_update(reader.position().read_update().expect("Update is readable")));
+        assert_eq!(uuids(&reader.search("again", 10)), [Uuid::from_u128(0)]);
+    }
+}